@@ -6,10 +6,10 @@ local get_weather = {
 
 
 function get_weather.exec(location)
-    location_uri = string.format(get_weather.base_location_uri, percent_encode({input=location}).output, os.getenv("OPEN_WEATHER_API_KEY"))
+    location_uri = string.format(get_weather.base_location_uri, percent_encode({input=location}).output, secret("OPEN_WEATHER_API_KEY"))
     location_result = http_get({uri=location_uri})
 
-    weather_uri = string.format(get_weather.base_weather_uri, location_result[1].lat, location_result[1].lon, os.getenv("OPEN_WEATHER_API_KEY"))
+    weather_uri = string.format(get_weather.base_weather_uri, location_result[1].lat, location_result[1].lon, secret("OPEN_WEATHER_API_KEY"))
     weather_result = http_get({uri=weather_uri})
 
     return weather_result
@@ -1,29 +1,50 @@
-use std::{io::Write, num::NonZeroU32};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    num::NonZeroU32,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use {
     anyhow::{bail, Context, Result},
+    base64::{engine::general_purpose::STANDARD as BASE64, Engine},
     llama_cpp_2::{
-        context::params::LlamaContextParams,
+        context::{
+            params::{LlamaContextParams, RopeScalingType},
+            LlamaContext,
+        },
         llama_backend::LlamaBackend,
         llama_batch::LlamaBatch,
-        model::{
-            params::LlamaModelParams,
-            LlamaModel, {AddBos, Special},
-        },
-        token::data_array::LlamaTokenDataArray,
+        model::{params::LlamaModelParams, AddBos, LlamaLoraAdapter, LlamaModel},
+        mtmd::{mtmd_default_marker, MtmdBitmap, MtmdContext, MtmdContextParams, MtmdInputText},
+        sampling::LlamaSampler,
+        token::LlamaToken,
     },
-    log::debug,
+    log::{debug, info, warn},
     minijinja::{context, Environment, Value},
     rand::prelude::*,
     serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
 };
 
-use crate::config::Model;
+use crate::{
+    cache::{CacheStats, DiskCache},
+    config::{Adapter, Hub, Model, ModelParams, Reasoning, ResponseCache, RopeScalingKind},
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
     role: String,
     content: String,
+    /// Thinking split out of the response by reasoning models. It is never rendered back into
+    /// the prompt so replayed history only carries the final answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reasoning: Option<String>,
+    /// Shown to the model ahead of the content. Needs a model with an `mmproj`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<Image>,
 }
 
 impl Message {
@@ -31,175 +52,1013 @@ impl Message {
         Self {
             role: String::from(role),
             content: String::from(content),
+            reasoning: None,
+            images: vec![],
+        }
+    }
+
+    /// The content as rendered into the prompt, with a media marker in place of each image for
+    /// mtmd to swap for the encoded image.
+    fn prompt_content(&self) -> String {
+        let mut content = String::new();
+        for _ in &self.images {
+            content.push_str(mtmd_default_marker());
+            content.push('\n');
+        }
+        content.push_str(&self.content);
+        content
+    }
+}
+
+/// An image attached to a message, given as a file path or base64 encoded data.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Image {
+    Path(PathBuf),
+    Base64(String),
+}
+
+impl Image {
+    pub fn load(&self) -> Result<Vec<u8>> {
+        match self {
+            Image::Path(path) => {
+                fs::read(path).with_context(|| format!("unable to read image {}", path.display()))
+            }
+            Image::Base64(data) => BASE64
+                .decode(data.trim())
+                .with_context(|| "image is not valid base64"),
         }
     }
 }
 
+/// Per-call options accepted by `llm_eval` alongside the messages.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EvalOptions {
+    pub adapters: Vec<AdapterSelection>,
+    /// Fixes sampling so the same prompt always gives the same response. Random if unset.
+    pub seed: Option<u32>,
+    /// Allows the response cache to be used for this call, when one is configured. Calls without
+    /// a `seed` are never cached, since their responses are meant to vary.
+    pub cache: bool,
+}
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        Self {
+            adapters: vec![],
+            seed: None,
+            cache: true,
+        }
+    }
+}
+
+/// Everything that determines a response, hashed to key the response cache.
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    model: &'a Model,
+    prompt: &'a str,
+    /// SHA-256 of each image, since the prompt only holds a marker for them.
+    images: Vec<String>,
+    seed: Option<u32>,
+    adapters: &'a [AdapterSelection],
+}
+
+/// A LoRA adapter to enable for a call, either by name at its configured scale or with an explicit
+/// scale.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AdapterSelection {
+    Name(String),
+    Scaled { name: String, scale: f32 },
+}
+
+impl AdapterSelection {
+    fn name(&self) -> &str {
+        match self {
+            AdapterSelection::Name(name) | AdapterSelection::Scaled { name, .. } => name,
+        }
+    }
+}
+
+/// The response to an evaluation along with token counts for the call.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Completion {
+    #[serde(flatten)]
+    pub message: Message,
+    pub usage: Usage,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draft: Option<DraftUsage>,
+}
+
+/// How many drafted tokens the main model agreed with when speculative decoding is enabled.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DraftUsage {
+    pub drafted_tokens: usize,
+    pub accepted_tokens: usize,
+    pub acceptance_rate: f32,
+}
+
+/// A model along with the LoRA adapters, draft model and vision projector loaded against it.
+/// Adapters and the projector are declared first so they are freed before the model they were
+/// created from.
+struct LoadedModel {
+    adapters: HashMap<String, LlamaLoraAdapter>,
+    draft: Option<LlamaModel>,
+    mmproj: Option<MtmdContext>,
+    llama: LlamaModel,
+}
+
+// SAFETY: `LlamaLoraAdapter` wraps a raw pointer so it isn't `Send`, but adapters are owned by the
+// model they were created from and only used through the worker, which is always behind a mutex.
+unsafe impl Send for LoadedModel {}
+
 pub struct AIWorker {
     backend: LlamaBackend,
-    model: LlamaModel,
+    model: Option<LoadedModel>,
+    model_config: Model,
+    hub: Hub,
+    cache: Option<DiskCache>,
+    /// Chat template of the configured model, kept across idle unloads so cached responses can be
+    /// served without loading the model again.
+    chat_template: Option<String>,
+    last_used: Instant,
 }
 
 impl AIWorker {
-    pub fn new(model: &Model) -> Result<Self> {
+    pub fn new(model: &Model, hub: &Hub) -> Result<Self> {
         let backend = LlamaBackend::init()?;
 
-        let model_path = model
-            .get_or_load()
-            .with_context(|| "failed to get model from args")?;
+        Self::check_offload(&backend, model)?;
 
-        let model_params = {
-            #[cfg(feature = "cublas")]
-            if !disable_gpu {
-                LlamaModelParams::default().with_n_gpu_layers(1000)
-            } else {
-                LlamaModelParams::default()
-            }
-            #[cfg(not(feature = "cublas"))]
-            LlamaModelParams::default()
+        let llama_model = if model.lazy {
+            info!("Deferring model load until first use");
+            None
+        } else {
+            Some(Self::load_from_file(&backend, model, hub)?)
         };
 
-        let model = LlamaModel::load_from_file(&backend, model_path, &model_params)
-            .with_context(|| "unable to load model")?;
+        Ok(Self {
+            backend,
+            model: llama_model,
+            model_config: model.clone(),
+            hub: hub.clone(),
+            cache: None,
+            chat_template: None,
+            last_used: Instant::now(),
+        })
+    }
 
-        Ok(Self { backend, model })
+    fn check_offload(backend: &LlamaBackend, model: &Model) -> Result<()> {
+        if model.params.n_gpu_layers > 0 && !backend.supports_gpu_offload() {
+            bail!(
+                "n_gpu_layers is {} but this build has no GPU offload support",
+                model.params.n_gpu_layers
+            );
+        }
+
+        Ok(())
     }
 
-    pub fn _load_model(&mut self, model: Model) -> Result<()> {
+    fn load_from_file(backend: &LlamaBackend, model: &Model, hub: &Hub) -> Result<LoadedModel> {
+        Self::check_offload(backend, model)?;
+
         let model_path = model
-            .get_or_load()
+            .get_or_load(hub)
             .with_context(|| "failed to get model from args")?;
 
-        let model_params = {
-            #[cfg(feature = "cublas")]
-            if !disable_gpu {
-                LlamaModelParams::default().with_n_gpu_layers(1000)
-            } else {
-                LlamaModelParams::default()
+        let model_params = LlamaModelParams::default()
+            .with_n_gpu_layers(model.params.n_gpu_layers)
+            .with_use_mmap(model.params.use_mmap)
+            .with_use_mlock(model.params.use_mlock);
+
+        let llama_model = LlamaModel::load_from_file(backend, model_path, &model_params)
+            .with_context(|| "unable to load model")?;
+
+        info!("Loaded model {:?}", model.source);
+
+        let mut adapters = HashMap::new();
+        for adapter in &model.adapters {
+            let lora = llama_model
+                .lora_adapter_init(&adapter.path)
+                .with_context(|| format!("unable to load adapter {}", adapter.path.display()))?;
+            info!(
+                "Loaded adapter {} from {}",
+                adapter.name,
+                adapter.path.display()
+            );
+            adapters.insert(adapter.name.clone(), lora);
+        }
+
+        let draft = match &model.draft {
+            Some(draft) => {
+                let draft_path = draft
+                    .source
+                    .get_or_load(hub)
+                    .with_context(|| "failed to get draft model")?;
+                let draft_model = LlamaModel::load_from_file(backend, draft_path, &model_params)
+                    .with_context(|| "unable to load draft model")?;
+
+                if draft_model.n_vocab() != llama_model.n_vocab() {
+                    bail!(
+                        "draft model vocabulary ({}) doesn't match the model ({})",
+                        draft_model.n_vocab(),
+                        llama_model.n_vocab()
+                    );
+                }
+
+                info!("Loaded draft model {:?}", draft.source);
+                Some(draft_model)
             }
-            #[cfg(not(feature = "cublas"))]
-            LlamaModelParams::default()
+            None => None,
         };
 
-        self.model = LlamaModel::load_from_file(&self.backend, model_path, &model_params)
-            .with_context(|| "unable to load model")?;
+        let mmproj = match &model.mmproj {
+            Some(mmproj) => {
+                let mmproj_path = mmproj
+                    .get_or_load(hub)
+                    .with_context(|| "failed to get vision projector")?;
+                // Images are encoded on the CPU so the projector doesn't compete with the model
+                // for GPU memory.
+                let mut mtmd_params = MtmdContextParams {
+                    use_gpu: false,
+                    print_timings: false,
+                    ..Default::default()
+                };
+                if let Some(n_threads) = model.params.n_threads {
+                    mtmd_params.n_threads = n_threads as i32;
+                }
+                let mtmd = MtmdContext::init_from_file(
+                    mmproj_path
+                        .to_str()
+                        .context("mmproj path isn't valid UTF-8")?,
+                    &llama_model,
+                    &mtmd_params,
+                )
+                .with_context(|| "unable to load vision projector")?;
+
+                if !mtmd.support_vision() {
+                    bail!("{} has no vision support", mmproj_path.display());
+                }
+
+                info!("Loaded vision projector {:?}", mmproj);
+                Some(mtmd)
+            }
+            None => None,
+        };
+
+        Ok(LoadedModel {
+            adapters,
+            draft,
+            mmproj,
+            llama: llama_model,
+        })
+    }
+
+    /// Swaps the loaded model in place. Callers hold the worker lock for the duration of the
+    /// load, so in-flight evaluations finish on the old model and anything queued behind the lock
+    /// runs against the new one. On failure the previous model stays loaded.
+    pub fn load_model(&mut self, model: &Model) -> Result<()> {
+        model.validate()?;
+
+        if !model.needs_reload(&self.model_config) {
+            info!("Requested model is already loaded");
+            self.model_config = model.clone();
+            return Ok(());
+        }
+
+        if model.lazy {
+            if self.model.take().is_some() {
+                info!("Unloaded model {:?}", self.model_config.source);
+            }
+        } else {
+            self.model = Some(Self::load_from_file(&self.backend, model, &self.hub)?);
+        }
+        self.model_config = model.clone();
+        self.chat_template = None;
+
+        Ok(())
+    }
+
+    /// Replaces the hub settings used by subsequent model loads.
+    pub fn set_hub(&mut self, hub: Hub) {
+        self.hub = hub;
+    }
+
+    /// Enables, disables or reconfigures the response cache. Hit and miss counts are kept if the
+    /// settings haven't changed.
+    pub fn set_cache(&mut self, config: Option<&ResponseCache>) -> Result<()> {
+        if self.cache.as_ref().map(DiskCache::config) != config {
+            self.cache = config.map(DiskCache::new).transpose()?;
+        }
 
         Ok(())
     }
 
-    fn llm_run(&mut self, prompt: &str) -> Result<Message> {
+    pub fn cache_stats(&self) -> Result<Option<CacheStats>> {
+        self.cache.as_ref().map(DiskCache::stats).transpose()
+    }
+
+    /// Loads the model if it was deferred or unloaded while idle.
+    fn ensure_loaded(&mut self) -> Result<()> {
+        self.last_used = Instant::now();
+
+        if self.model.is_none() {
+            self.model = Some(Self::load_from_file(
+                &self.backend,
+                &self.model_config,
+                &self.hub,
+            )?);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the model's chat template, loading the model only if the template hasn't been read
+    /// since the model was configured.
+    fn chat_template(&mut self) -> Result<String> {
+        if let Some(template) = &self.chat_template {
+            return Ok(template.clone());
+        }
+
+        self.ensure_loaded()?;
+        let loaded = self.model.as_ref().context("model not loaded")?;
+        let template = loaded.llama.chat_template(None)?.to_str()?.to_string();
+        self.chat_template = Some(template.clone());
+
+        Ok(template)
+    }
+
+    /// Drops the model if it hasn't been used for the configured `idle_unload` period. The
+    /// backend stays initialised so the next evaluation only pays for the model load.
+    pub fn unload_if_idle(&mut self) {
+        let Some(idle_unload) = self.model_config.idle_unload else {
+            return;
+        };
+
+        if self.model.is_some() && self.last_used.elapsed() >= Duration::from_secs(idle_unload) {
+            self.model = None;
+            info!(
+                "Unloaded model {:?} after {}s idle",
+                self.model_config.source, idle_unload
+            );
+        }
+    }
+
+    fn llm_run(
+        &mut self,
+        prompt: &str,
+        images: &[Vec<u8>],
+        options: &EvalOptions,
+    ) -> Result<Completion> {
+        let loaded = self.model.as_mut().context("model not loaded")?;
+        let model = &loaded.llama;
+        let params = &self.model_config.params;
         let mut rng = rand::thread_rng();
-        let ctx_params = LlamaContextParams::default()
-            .with_n_ctx(Some(NonZeroU32::new(1024 * 15).unwrap()))
-            .with_n_batch(1024 * 15)
-            .with_seed(rng.gen());
-
-        let mut ctx = self
-            .model
-            .new_context(&self.backend, ctx_params)
+        let mut sampler = LlamaSampler::dist(options.seed.unwrap_or_else(|| rng.gen()));
+
+        let mut ctx = model
+            .new_context(&self.backend, context_params(params))
             .with_context(|| "unable to create the llama_context")?;
 
-        let tokens_list = self
-            .model
-            .str_to_token(prompt, AddBos::Always)
-            .with_context(|| format!("failed to tokenize {prompt}"))?;
+        if let Some((name, scale)) = select_adapter(&options.adapters, &self.model_config.adapters)?
+        {
+            let adapter = loaded
+                .adapters
+                .get_mut(name)
+                .with_context(|| format!("unknown adapter {}", name))?;
+            ctx.lora_adapter_set(adapter, scale)
+                .with_context(|| format!("unable to apply adapter {}", name))?;
+            debug!("Applied adapter {} with scale {}", name, scale);
+        }
 
         let n_cxt = ctx.n_ctx() as i32;
-        let n_len = prompt.len() as i32;
-        let n_kv_req = tokens_list.len() as i32 + (n_len - tokens_list.len() as i32);
+        let mut batch = LlamaBatch::new(ctx.n_batch() as usize, 1);
+        let mut usage = Usage::default();
 
-        debug!("n_len = {n_len}, n_ctx = {n_cxt}, k_kv_req = {n_kv_req}");
+        // Prompts with images are tokenized and decoded through mtmd, which can't feed the draft
+        // model, so only text prompts keep their tokens for speculative decoding.
+        let (tokens_list, n_cur, max_tokens) = if images.is_empty() {
+            let tokens_list = model
+                .str_to_token(prompt, AddBos::Always)
+                .with_context(|| format!("failed to tokenize {prompt}"))?;
 
-        if n_kv_req > n_cxt {
-            bail!(
-                "n_kv_req > n_ctx, the required kv cache size is not big enough either reduce n_len or increase n_ctx"
-            )
+            let n_len = prompt.len() as i32;
+            let n_kv_req = tokens_list.len() as i32 + (n_len - tokens_list.len() as i32);
+
+            debug!("n_len = {n_len}, n_ctx = {n_cxt}, k_kv_req = {n_kv_req}");
+
+            if n_kv_req > n_cxt {
+                bail!(
+                    "n_kv_req > n_ctx, the required kv cache size is not big enough either reduce n_len or increase n_ctx"
+                )
+            }
+
+            if tokens_list.len() >= usize::try_from(n_len)? {
+                bail!("Prompt is too long. Cannot have more than {n_len} tokens.")
+            }
+
+            std::io::stderr().flush()?;
+
+            let last_index: i32 = (tokens_list.len() - 1) as i32;
+
+            for (i, token) in (0_i32..).zip(tokens_list.iter().copied()) {
+                let is_last = i == last_index;
+                batch.add(token, i, &[0], is_last)?;
+            }
+
+            ctx.decode(&mut batch)
+                .with_context(|| "llama_decode() failed")?;
+
+            usage.prompt_tokens = tokens_list.len();
+            let max_tokens = usize::try_from(n_len - batch.n_tokens() + 1).unwrap_or(0);
+            (Some(tokens_list), batch.n_tokens(), max_tokens)
+        } else {
+            let mtmd = loaded
+                .mmproj
+                .as_ref()
+                .context("messages contain images but the model has no mmproj configured")?;
+
+            let bitmaps = images
+                .iter()
+                .map(|image| MtmdBitmap::from_buffer(mtmd, image))
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| "unable to decode image")?;
+            let chunks = mtmd
+                .tokenize(
+                    MtmdInputText {
+                        text: prompt.to_string(),
+                        add_special: true,
+                        parse_special: true,
+                    },
+                    &bitmaps.iter().collect::<Vec<_>>(),
+                )
+                .with_context(|| "failed to tokenize prompt with images")?;
+
+            if chunks.total_positions() >= n_cxt {
+                bail!(
+                    "prompt with images needs {} positions but n_ctx is {}",
+                    chunks.total_positions(),
+                    n_cxt
+                );
+            }
+
+            debug!(
+                "Encoding {} image(s) into {} prompt tokens",
+                images.len(),
+                chunks.total_tokens()
+            );
+
+            let n_past = chunks
+                .eval_chunks(mtmd, &ctx, 0, 0, ctx.n_batch() as i32, true)
+                .with_context(|| "failed to eval prompt with images")?;
+
+            usage.prompt_tokens = chunks.total_tokens();
+            (None, n_past, usize::try_from(n_cxt - n_past).unwrap_or(0))
+        };
+
+        let reasoning = self.model_config.reasoning.as_ref();
+        let mut budget = ReasoningBudget::new(model, reasoning, prompt)?;
+
+        let tokens = match (&loaded.draft, &self.model_config.draft, &tokens_list) {
+            (Some(draft_model), Some(draft), Some(tokens_list)) => {
+                let mut drafter = Drafter {
+                    ctx: draft_model
+                        .new_context(&self.backend, context_params(params))
+                        .with_context(|| "unable to create the draft llama_context")?,
+                    length: draft.length,
+                    usage: DraftUsage::default(),
+                };
+                let tokens = speculate(
+                    model,
+                    &mut ctx,
+                    &mut sampler,
+                    &mut drafter,
+                    tokens_list,
+                    max_tokens,
+                    &mut budget,
+                )?;
+                usage.draft = Some(drafter.usage);
+                tokens
+            }
+            _ => sample(
+                model,
+                &mut ctx,
+                &mut sampler,
+                &mut batch,
+                n_cur,
+                max_tokens,
+                &mut budget,
+            )?,
+        };
+
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        let mut result = String::new();
+
+        for token in &tokens {
+            result.push_str(&model.token_to_piece(*token, &mut decoder, true, None)?);
+        }
+
+        usage.completion_tokens = tokens.len();
+
+        debug!("{}", result);
+        debug!("{:?}", usage);
+
+        let message = match reasoning {
+            Some(reasoning) => {
+                let (thoughts, content) =
+                    split_reasoning(&result, reasoning, opens_reasoning(prompt, reasoning));
+                Message {
+                    reasoning: thoughts,
+                    ..Message::new("assistant", &content)
+                }
+            }
+            None => Message::new("assistant", &result),
+        };
+
+        Ok(Completion { message, usage })
+    }
+
+    pub fn eval(&mut self, messages: &[Message], options: &EvalOptions) -> Result<Completion> {
+        let images = messages
+            .iter()
+            .flat_map(|message| &message.images)
+            .map(Image::load)
+            .collect::<Result<Vec<_>>>()?;
+
+        let prompt = build_prompt(messages, &self.chat_template()?)?;
+
+        debug!("Prompt: {}", prompt);
+
+        let key = match &self.cache {
+            Some(_) if options.cache && options.seed.is_some() => {
+                Some(DiskCache::key(&CacheKey {
+                    // Settings that only decide when the model is resident don't change responses.
+                    model: &Model {
+                        lazy: false,
+                        idle_unload: None,
+                        ..self.model_config.clone()
+                    },
+                    prompt: &prompt,
+                    images: images
+                        .iter()
+                        .map(|image| format!("{:x}", Sha256::digest(image)))
+                        .collect(),
+                    seed: options.seed,
+                    adapters: &options.adapters,
+                })?)
+            }
+            _ => None,
+        };
+
+        if let (Some(cache), Some(key)) = (self.cache.as_mut(), &key) {
+            if let Some(completion) = cache.get::<Completion>(key) {
+                return Ok(completion);
+            }
         }
 
-        if tokens_list.len() >= usize::try_from(n_len)? {
-            bail!("Prompt is too long. Cannot have more than {n_len} tokens.")
+        self.ensure_loaded()?;
+        let completion = self.llm_run(&prompt, &images, options)?;
+
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            if let Err(e) = cache.put(key, &completion) {
+                warn!("Unable to cache response: {}", e);
+            }
         }
 
-        std::io::stderr().flush()?;
+        Ok(completion)
+    }
+}
 
-        let mut batch = LlamaBatch::new(ctx.n_batch() as usize, 1);
-        let last_index: i32 = (tokens_list.len() - 1) as i32;
+fn build_prompt(messages: &[Message], chat_template: &str) -> Result<String> {
+    let messages: Vec<Value> = messages
+        .iter()
+        .map(|message| context! { role => message.role, content => message.prompt_content() })
+        .collect();
+
+    let ctx = context! {
+        add_generation_prompt => true,
+        tools_in_user_message => false,
+        // bos_token => "<|begin_of_text|>",
+        messages => messages,
+    };
+
+    let env = Environment::new();
+    Ok(env.render_str(chat_template, ctx)?)
+}
 
-        for (i, token) in (0_i32..).zip(tokens_list.into_iter()) {
-            let is_last = i == last_index;
-            batch.add(token, i, &[0], is_last)?;
+/// Resolves the adapters selected for a call to the one to apply and its scale. Setting an adapter
+/// replaces any already set on the context, and the bindings only set one at a time, so a call
+/// can't combine several.
+fn select_adapter<'a>(
+    selections: &'a [AdapterSelection],
+    adapters: &[Adapter],
+) -> Result<Option<(&'a str, f32)>> {
+    let selection = match selections {
+        [] => return Ok(None),
+        [selection] => selection,
+        _ => bail!(
+            "only one adapter can be applied per call, got {}",
+            selections
+                .iter()
+                .map(AdapterSelection::name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    let name = selection.name();
+    let adapter = adapters
+        .iter()
+        .find(|adapter| adapter.name == name)
+        .with_context(|| format!("unknown adapter {}", name))?;
+    let scale = match selection {
+        AdapterSelection::Scaled { scale, .. } => *scale,
+        AdapterSelection::Name(_) => adapter.scale,
+    };
+
+    Ok(Some((name, scale)))
+}
+
+fn context_params(params: &ModelParams) -> LlamaContextParams {
+    let mut ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(params.n_ctx))
+        .with_n_batch(params.n_batch);
+
+    if let Some(n_ubatch) = params.n_ubatch {
+        ctx_params = ctx_params.with_n_ubatch(n_ubatch);
+    }
+
+    if let Some(n_threads) = params.n_threads {
+        ctx_params = ctx_params
+            .with_n_threads(n_threads as i32)
+            .with_n_threads_batch(n_threads as i32);
+    }
+
+    if let Some(rope_scaling) = &params.rope_scaling {
+        ctx_params = ctx_params.with_rope_scaling_type(match rope_scaling.scaling_type {
+            RopeScalingKind::None => RopeScalingType::None,
+            RopeScalingKind::Linear => RopeScalingType::Linear,
+            RopeScalingKind::Yarn => RopeScalingType::Yarn,
+        });
+
+        if let Some(freq_base) = rope_scaling.freq_base {
+            ctx_params = ctx_params.with_rope_freq_base(freq_base);
         }
 
-        ctx.decode(&mut batch)
-            .with_context(|| "llama_decode() failed")?;
+        if let Some(freq_scale) = rope_scaling.freq_scale {
+            ctx_params = ctx_params.with_rope_freq_scale(freq_scale);
+        }
+    }
 
-        let mut n_cur = batch.n_tokens();
-        let mut decoder = encoding_rs::UTF_8.new_decoder();
-        let mut result = vec![];
+    ctx_params
+}
+
+/// Generates one token per decode until end of stream or `max_tokens`. Expects the prompt to
+/// already be decoded into `ctx`, ending just before `n_cur`.
+fn sample(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    sampler: &mut LlamaSampler,
+    batch: &mut LlamaBatch,
+    mut n_cur: i32,
+    max_tokens: usize,
+    budget: &mut ReasoningBudget,
+) -> Result<Vec<LlamaToken>> {
+    let mut tokens = vec![];
+
+    while tokens.len() < max_tokens {
+        // Only the last token of each decode has logits, whether it came from `batch` or mtmd.
+        let new_token_id = sampler.sample(ctx, -1);
+
+        if new_token_id == model.token_eos() {
+            debug!("Hit end of stream");
+            break;
+        }
+
+        tokens.push(new_token_id);
+
+        batch.clear();
+        batch.add(new_token_id, n_cur, &[0], true)?;
+        n_cur += 1;
+
+        if let Some(forced) = budget.observe(model, new_token_id)? {
+            batch.clear();
+            batch.add(new_token_id, n_cur - 1, &[0], false)?;
+            for (i, token) in (0_i32..).zip(forced.iter().copied()) {
+                batch.add(token, n_cur + i, &[0], i == forced.len() as i32 - 1)?;
+            }
+            n_cur += forced.len() as i32;
+            tokens.extend(forced);
+        }
+
+        ctx.decode(batch).with_context(|| "failed to eval")?;
+    }
+
+    Ok(tokens)
+}
+
+/// The draft model's context and settings for a speculative run.
+struct Drafter<'a> {
+    ctx: LlamaContext<'a>,
+    length: usize,
+    usage: DraftUsage,
+}
+
+/// Generates with a draft model proposing up to `draft.length` tokens that the main model then
+/// checks in a single decode. Every token is still sampled from the main model's logits in the
+/// same order as [`sample`], so the output is identical and drafts only save decodes when they
+/// agree with what would have been sampled anyway. Expects the prompt to already be decoded into
+/// `ctx` but not the draft context.
+fn speculate(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    sampler: &mut LlamaSampler,
+    draft: &mut Drafter,
+    prompt: &[LlamaToken],
+    max_tokens: usize,
+    budget: &mut ReasoningBudget,
+) -> Result<Vec<LlamaToken>> {
+    let mut tokens = vec![];
+
+    if max_tokens == 0 {
+        return Ok(tokens);
+    }
+
+    let mut draft_batch = LlamaBatch::new(draft.ctx.n_batch() as usize, 1);
+    for (i, token) in (0_i32..).zip(prompt.iter().copied()) {
+        draft_batch.add(token, i, &[0], false)?;
+    }
+    draft
+        .ctx
+        .decode(&mut draft_batch)
+        .with_context(|| "draft llama_decode() failed")?;
+
+    let mut batch = LlamaBatch::new(ctx.n_batch() as usize, 1);
+    let mut n_cur = prompt.len() as i32;
+    let mut token = sampler.sample(ctx, n_cur - 1);
+
+    // Decodes `inputs` from `n_cur` in both contexts and samples what follows them.
+    let mut inject = |ctx: &mut LlamaContext,
+                      draft_ctx: &mut LlamaContext,
+                      sampler: &mut LlamaSampler,
+                      inputs: &[LlamaToken],
+                      n_cur: i32|
+     -> Result<LlamaToken> {
+        let last = inputs.len() as i32 - 1;
+
+        batch.clear();
+        for (i, input) in (0_i32..).zip(inputs.iter().copied()) {
+            batch.add(input, n_cur + i, &[0], i == last)?;
+        }
+        ctx.decode(&mut batch).with_context(|| "failed to eval")?;
+
+        batch.clear();
+        for (i, input) in (0_i32..).zip(inputs.iter().copied()) {
+            batch.add(input, n_cur + i, &[0], false)?;
+        }
+        draft_ctx
+            .decode(&mut batch)
+            .with_context(|| "failed to eval draft")?;
+
+        Ok(sampler.sample(ctx, last))
+    };
+
+    // `token` has been sampled but isn't in either KV cache yet; it sits at `n_cur`.
+    loop {
+        if token == model.token_eos() {
+            debug!("Hit end of stream");
+            break;
+        }
+
+        tokens.push(token);
+
+        if tokens.len() >= max_tokens {
+            break;
+        }
+
+        if let Some(forced) = budget.observe(model, token)? {
+            let mut inputs = vec![token];
+            inputs.extend(forced.iter().copied());
+            tokens.extend(forced);
+            token = inject(ctx, &mut draft.ctx, sampler, &inputs, n_cur)?;
+            n_cur += inputs.len() as i32;
+            continue;
+        }
+
+        let mut drafted = vec![];
+        let mut draft_token = token;
+        for i in 0..draft.length.min(max_tokens - tokens.len()) {
+            draft_batch.clear();
+            draft_batch.add(draft_token, n_cur + i as i32, &[0], true)?;
+            draft
+                .ctx
+                .decode(&mut draft_batch)
+                .with_context(|| "failed to eval draft")?;
+            draft_token = draft
+                .ctx
+                .candidates_ith(0)
+                .max_by(|a, b| a.logit().total_cmp(&b.logit()))
+                .map(|data| data.id())
+                .context("draft model produced no candidates")?;
+            drafted.push(draft_token);
+        }
+
+        let mut verify_batch = LlamaBatch::new(drafted.len() + 1, 1);
+        verify_batch.add(token, n_cur, &[0], true)?;
+        for (i, drafted_token) in (1_i32..).zip(drafted.iter().copied()) {
+            verify_batch.add(drafted_token, n_cur + i, &[0], true)?;
+        }
+        ctx.decode(&mut verify_batch)
+            .with_context(|| "failed to eval")?;
 
-        while n_cur <= n_len {
+        draft.usage.drafted_tokens += drafted.len();
+
+        let mut accepted = 0;
+        let mut forced = None;
+        let next = loop {
+            let sampled = sampler.sample(ctx, accepted as i32);
+
+            if accepted < drafted.len()
+                && sampled == drafted[accepted]
+                && sampled != model.token_eos()
+                && tokens.len() < max_tokens
             {
-                let candidates = ctx.candidates_ith(batch.n_tokens() - 1);
-                let mut candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
-                let new_token_id = candidates_p.sample_token(&mut ctx);
+                tokens.push(sampled);
+                accepted += 1;
 
-                if new_token_id == self.model.token_eos() {
-                    debug!("Hit end of stream");
-                    break;
+                forced = budget.observe(model, sampled)?;
+                if forced.is_some() {
+                    break sampled;
                 }
+                continue;
+            }
 
-                let output_bytes = self.model.token_to_bytes(new_token_id, Special::Tokenize)?;
-                let mut output_string = String::with_capacity(32);
-                let _decode_result =
-                    decoder.decode_to_string(&output_bytes, &mut output_string, false);
-                std::io::stdout().flush()?;
-                result.push(output_string);
+            break sampled;
+        };
 
-                batch.clear();
-                batch.add(new_token_id, n_cur, &[0], true)?;
-            }
+        draft.usage.accepted_tokens += accepted;
 
-            n_cur += 1;
+        if tokens.len() >= max_tokens {
+            break;
+        }
+
+        // Drop the rejected drafts so both caches end just before `next`.
+        let (next_pos, catch_up) = settle(n_cur, &drafted, accepted);
+        n_cur = next_pos;
+        ctx.clear_kv_cache_seq(Some(0), Some(n_cur as u32), None)
+            .with_context(|| "failed to clear kv cache")?;
+        draft
+            .ctx
+            .clear_kv_cache_seq(Some(0), Some(n_cur as u32), None)
+            .with_context(|| "failed to clear draft kv cache")?;
 
-            ctx.decode(&mut batch).with_context(|| "failed to eval")?;
+        if let Some((last, pos)) = catch_up {
+            draft_batch.clear();
+            draft_batch.add(last, pos, &[0], false)?;
+            draft
+                .ctx
+                .decode(&mut draft_batch)
+                .with_context(|| "failed to eval draft")?;
         }
 
-        debug!("{}", result.join(""));
+        token = match forced {
+            Some(forced) => {
+                tokens.extend(forced.iter().copied());
+                let next = inject(ctx, &mut draft.ctx, sampler, &forced, n_cur)?;
+                n_cur += forced.len() as i32;
+                next
+            }
+            None => next,
+        };
+    }
 
-        Ok(Message::new("assistant", &result.join("")))
+    if draft.usage.drafted_tokens > 0 {
+        draft.usage.acceptance_rate =
+            draft.usage.accepted_tokens as f32 / draft.usage.drafted_tokens as f32;
     }
 
-    pub fn eval(&mut self, messages: &[Message]) -> Result<Message> {
-        debug!("Chat Template: {}", &self.model.get_chat_template(8192)?);
+    Ok(tokens)
+}
 
-        let prompt = self.build_prompt(messages)?;
+/// Works out where a verification round that started at `n_cur` leaves the caches. Returns the
+/// position of the token sampled after the accepted drafts, and the drafted token still missing
+/// from the draft cache when every draft was accepted: drafting decodes each proposal only to
+/// propose the next one, so the last is never decoded into the draft context.
+fn settle(n_cur: i32, drafted: &[LlamaToken], accepted: usize) -> (i32, Option<(LlamaToken, i32)>) {
+    let next_pos = n_cur + accepted as i32 + 1;
 
-        debug!("Prompt: {}", prompt);
+    match drafted.last() {
+        Some(&last) if accepted == drafted.len() => (next_pos, Some((last, next_pos - 1))),
+        _ => (next_pos, None),
+    }
+}
+
+/// Follows generation in and out of a reasoning block, forcing the end delimiter once the
+/// configured budget of reasoning tokens is spent. Only the first block is budgeted.
+struct ReasoningBudget {
+    reasoning: Option<Reasoning>,
+    end_tokens: Vec<LlamaToken>,
+    decoder: encoding_rs::Decoder,
+    text: String,
+    in_reasoning: bool,
+    done: bool,
+    used: usize,
+}
+
+impl ReasoningBudget {
+    fn new(model: &LlamaModel, reasoning: Option<&Reasoning>, prompt: &str) -> Result<Self> {
+        let reasoning = reasoning.filter(|reasoning| reasoning.budget.is_some());
+        let end_tokens = match reasoning {
+            Some(reasoning) => model
+                .str_to_token(&reasoning.end, AddBos::Never)
+                .with_context(|| format!("failed to tokenize {}", reasoning.end))?,
+            None => vec![],
+        };
 
-        self.llm_run(&prompt)
+        Ok(Self {
+            in_reasoning: reasoning.is_some_and(|reasoning| opens_reasoning(prompt, reasoning)),
+            reasoning: reasoning.cloned(),
+            end_tokens,
+            decoder: encoding_rs::UTF_8.new_decoder(),
+            text: String::new(),
+            done: false,
+            used: 0,
+        })
     }
 
-    fn build_prompt(&self, messages: &[Message]) -> Result<String> {
-        let messages: Vec<Value> = messages
-            .iter()
-            .map(|message| context! { role => message.role, content => message.content })
-            .collect();
-
-        let ctx = context! {
-            add_generation_prompt => true,
-            tools_in_user_message => false,
-            // bos_token => "<|begin_of_text|>",
-            messages => messages,
+    /// Records a generated token and returns the tokens to force in after it, if any.
+    fn observe(
+        &mut self,
+        model: &LlamaModel,
+        token: LlamaToken,
+    ) -> Result<Option<Vec<LlamaToken>>> {
+        let (Some(reasoning), false) = (&self.reasoning, self.done) else {
+            return Ok(None);
         };
 
-        let env = Environment::new();
-        Ok(env.render_str(&self.model.get_chat_template(8192)?, ctx)?)
+        self.text
+            .push_str(&model.token_to_piece(token, &mut self.decoder, true, None)?);
+
+        if !self.in_reasoning {
+            if let Some(start) = self.text.find(&reasoning.start) {
+                self.text.drain(..start + reasoning.start.len());
+                self.in_reasoning = true;
+            }
+            return Ok(None);
+        }
+
+        if self.text.contains(&reasoning.end) {
+            self.done = true;
+            return Ok(None);
+        }
+
+        self.used += 1;
+        if reasoning.budget.is_some_and(|budget| self.used >= budget) {
+            debug!("Reasoning budget of {} tokens spent", self.used);
+            self.done = true;
+            return Ok(Some(self.end_tokens.clone()));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Whether the rendered prompt leaves a reasoning block open for the model to continue, as some
+/// chat templates add the start delimiter to the generation prompt.
+fn opens_reasoning(prompt: &str, reasoning: &Reasoning) -> bool {
+    prompt.trim_end().ends_with(&reasoning.start)
+}
+
+/// Splits generated text into the reasoning and the final answer.
+fn split_reasoning(text: &str, reasoning: &Reasoning, opened: bool) -> (Option<String>, String) {
+    let (before, rest) = match text.find(&reasoning.start) {
+        Some(start) if !opened => (&text[..start], &text[start + reasoning.start.len()..]),
+        _ if opened => ("", text),
+        _ => return (None, text.trim().to_string()),
+    };
+
+    match rest.find(&reasoning.end) {
+        Some(end) => (
+            Some(rest[..end].trim().to_string()),
+            format!("{}{}", before, &rest[end + reasoning.end.len()..])
+                .trim()
+                .to_string(),
+        ),
+        None => (Some(rest.trim().to_string()), before.trim().to_string()),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::ModelSource;
     #[test]
     fn test_llm_interface() {
         env_logger::init();
@@ -208,12 +1067,164 @@ mod test {
             Message::new("user", "How are you today?"),
         ];
 
-        let model = Model::HuggingFace {
-            repo: String::from(""),
-            model: String::from(""),
+        let model = Model {
+            source: ModelSource::HuggingFace {
+                repo: String::from(""),
+                model: String::from(""),
+                revision: None,
+            },
+            lazy: false,
+            idle_unload: None,
+            params: ModelParams::default(),
+            adapters: vec![],
+            draft: None,
+            reasoning: None,
+            mmproj: None,
         };
 
-        let mut llm = AIWorker::new(&model).unwrap();
-        llm.eval(&messages).unwrap();
+        let mut llm = AIWorker::new(&model, &Hub::default()).unwrap();
+        llm.eval(&messages, &EvalOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn test_message_images() {
+        let path = std::env::temp_dir().join(format!("salient-image-{}", std::process::id()));
+        fs::write(&path, b"\x89PNG").unwrap();
+
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": "What does the chart show?",
+            "images": [{ "path": path }, { "base64": "iVBORw==" }],
+        }))
+        .unwrap();
+
+        let images = message
+            .images
+            .iter()
+            .map(Image::load)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(images, [b"\x89PNG".to_vec(), b"\x89PNG".to_vec()]);
+
+        let marker = mtmd_default_marker();
+        assert_eq!(
+            message.prompt_content(),
+            format!("{marker}\n{marker}\nWhat does the chart show?")
+        );
+        assert_eq!(Message::new("user", "Hello!").prompt_content(), "Hello!");
+        assert!(Image::Base64(String::from("not base64!")).load().is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_select_adapter() {
+        let adapters = [
+            Adapter {
+                name: String::from("sql"),
+                path: PathBuf::from("/models/sql-lora.gguf"),
+                scale: 0.8,
+            },
+            Adapter {
+                name: String::from("tone"),
+                path: PathBuf::from("/models/tone-lora.gguf"),
+                scale: 1.0,
+            },
+        ];
+        let selections: Vec<AdapterSelection> =
+            serde_json::from_value(serde_json::json!(["sql", { "name": "tone", "scale": 0.5 }]))
+                .unwrap();
+
+        assert_eq!(select_adapter(&[], &adapters).unwrap(), None);
+        assert_eq!(
+            select_adapter(&selections[..1], &adapters).unwrap(),
+            Some(("sql", 0.8))
+        );
+        assert_eq!(
+            select_adapter(&selections[1..], &adapters).unwrap(),
+            Some(("tone", 0.5))
+        );
+
+        // llama.cpp would keep only the last, so several are refused rather than dropped.
+        let error = select_adapter(&selections, &adapters).unwrap_err();
+        assert!(error.to_string().contains("sql, tone"));
+        assert!(
+            select_adapter(&[AdapterSelection::Name(String::from("nope"))], &adapters).is_err()
+        );
+    }
+
+    #[test]
+    fn test_build_prompt() {
+        let template = "{% for message in messages %}<{{ message.role }}>{{ message.content }}\n{% endfor %}{% if add_generation_prompt %}<assistant>{% endif %}";
+        let messages = [
+            Message::new("system", "Be brief."),
+            Message::new("user", "Hi"),
+        ];
+
+        assert_eq!(
+            build_prompt(&messages, template).unwrap(),
+            "<system>Be brief.\n<user>Hi\n<assistant>"
+        );
+    }
+
+    #[test]
+    fn test_settle() {
+        let drafted = [LlamaToken(7), LlamaToken(8), LlamaToken(9)];
+        let n_cur = 10;
+
+        for accepted in 0..=drafted.len() {
+            // The verify batch holds the current token and every draft, the draft context only
+            // the current token and all but the last draft.
+            let mut target_end = n_cur + drafted.len() as i32 + 1;
+            let mut draft_end = n_cur + drafted.len() as i32;
+
+            let (next_pos, catch_up) = settle(n_cur, &drafted, accepted);
+            target_end = target_end.min(next_pos);
+            draft_end = draft_end.min(next_pos);
+            if let Some((token, pos)) = catch_up {
+                assert_eq!(token, drafted[drafted.len() - 1]);
+                assert_eq!(pos, draft_end);
+                draft_end = pos + 1;
+            }
+
+            assert_eq!(next_pos, n_cur + accepted as i32 + 1);
+            assert_eq!(target_end, next_pos);
+            assert_eq!(draft_end, target_end);
+        }
+    }
+
+    #[test]
+    fn test_split_reasoning() {
+        let reasoning = Reasoning {
+            start: String::from("<think>"),
+            end: String::from("</think>"),
+            budget: None,
+        };
+
+        let test_values = vec![
+            (
+                "<think>\nThe user wants a greeting.\n</think>\n\nHello!",
+                false,
+                (Some("The user wants a greeting."), "Hello!"),
+            ),
+            (
+                "The user wants a greeting.</think>Hello!",
+                true,
+                (Some("The user wants a greeting."), "Hello!"),
+            ),
+            (
+                "<think>Still thinking when the budget ran out",
+                false,
+                (Some("Still thinking when the budget ran out"), ""),
+            ),
+            ("Hello!", false, (None, "Hello!")),
+        ];
+
+        for (text, opened, (thoughts, content)) in test_values {
+            assert_eq!(
+                split_reasoning(text, &reasoning, opened),
+                (thoughts.map(String::from), String::from(content))
+            );
+        }
     }
 }
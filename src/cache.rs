@@ -0,0 +1,251 @@
+use std::{
+    cmp::Reverse,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use {
+    anyhow::{Context, Result},
+    log::{info, warn},
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+};
+
+use crate::config::ResponseCache;
+
+const ENTRY_EXTENSION: &str = "json";
+
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    /// Seconds since the unix epoch when the entry was written.
+    created: u64,
+    value: T,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub size: u64,
+}
+
+/// Stores values as one JSON file per key. A file's modified time records when it was last used,
+/// which is what eviction goes by.
+pub struct DiskCache {
+    config: ResponseCache,
+    hits: u64,
+    misses: u64,
+}
+
+impl DiskCache {
+    pub fn new(config: &ResponseCache) -> Result<Self> {
+        fs::create_dir_all(&config.path)
+            .with_context(|| format!("unable to create cache dir {}", config.path.display()))?;
+
+        Ok(Self {
+            config: config.clone(),
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    pub fn config(&self) -> &ResponseCache {
+        &self.config
+    }
+
+    /// Hashes anything serializable into a key. Field order is fixed by the type so equal values
+    /// always produce the same key.
+    pub fn key(parts: &impl Serialize) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_vec(parts)?);
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    pub fn get<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        let value = self.read(&self.entry_path(key));
+
+        if value.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        info!(
+            "Response cache {} ({} hits, {} misses)",
+            if value.is_some() { "hit" } else { "miss" },
+            self.hits,
+            self.misses
+        );
+
+        value
+    }
+
+    pub fn put<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        let path = self.entry_path(key);
+        let tmp = path.with_extension("tmp");
+        let entry = Entry {
+            created: now(),
+            value,
+        };
+
+        fs::write(&tmp, serde_json::to_vec(&entry)?)
+            .with_context(|| format!("unable to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("unable to write {}", path.display()))?;
+
+        self.evict()
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let entries = self.entries()?;
+
+        Ok(CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: entries.len(),
+            size: entries.iter().map(|(_, size, _)| size).sum(),
+        })
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.config.path.join(key).with_extension(ENTRY_EXTENSION)
+    }
+
+    fn read<T: DeserializeOwned>(&self, path: &Path) -> Option<T> {
+        let file = File::options().read(true).write(true).open(path).ok()?;
+
+        let entry: Entry<T> = match serde_json::from_reader(BufReader::new(&file)) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(
+                    "Discarding unreadable cache entry {}: {}",
+                    path.display(),
+                    e
+                );
+                let _ = fs::remove_file(path);
+                return None;
+            }
+        };
+
+        if self
+            .config
+            .ttl
+            .is_some_and(|ttl| now().saturating_sub(entry.created) >= ttl)
+        {
+            let _ = fs::remove_file(path);
+            return None;
+        }
+
+        let _ = file.set_modified(SystemTime::now());
+
+        Some(entry.value)
+    }
+
+    /// Removes the least recently used entries until the cache fits in `max_size`.
+    fn evict(&self) -> Result<()> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|(_, _, modified)| Reverse(*modified));
+
+        let mut size = 0;
+        for (path, entry_size, _) in entries {
+            size += entry_size;
+            if size > self.config.max_size {
+                fs::remove_file(&path)
+                    .with_context(|| format!("unable to evict {}", path.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = vec![];
+
+        for entry in fs::read_dir(&self.config.path)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) {
+                let metadata = fs::metadata(&path)?;
+                entries.push((path, metadata.len(), metadata.modified()?));
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn test_cache(name: &str, ttl: Option<u64>, max_size: u64) -> DiskCache {
+        let path = std::env::temp_dir().join(format!("salient-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        DiskCache::new(&ResponseCache {
+            path,
+            ttl,
+            max_size,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cache_hit_and_miss() {
+        let mut cache = test_cache("hit", None, 1024);
+        let key = DiskCache::key(&("model", "prompt", Some(42))).unwrap();
+
+        assert_eq!(cache.get::<String>(&key), None);
+        cache.put(&key, &String::from("response")).unwrap();
+        assert_eq!(cache.get::<String>(&key), Some(String::from("response")));
+
+        let other = DiskCache::key(&("model", "prompt", Some(43))).unwrap();
+        assert_ne!(key, other);
+        assert_eq!(cache.get::<String>(&other), None);
+
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+
+        fs::remove_dir_all(&cache.config.path).unwrap();
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let mut cache = test_cache("ttl", Some(0), 1024);
+
+        cache.put("key", &1).unwrap();
+        assert_eq!(cache.get::<u32>("key"), None);
+        assert_eq!(cache.stats().unwrap().entries, 0);
+
+        fs::remove_dir_all(&cache.config.path).unwrap();
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let mut cache = test_cache("evict", None, 1024);
+        cache.put("old", &1).unwrap();
+        File::options()
+            .write(true)
+            .open(cache.entry_path("old"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+
+        // Room for one entry but not two.
+        let entry_size = fs::metadata(cache.entry_path("old")).unwrap().len();
+        cache.config.max_size = entry_size * 3 / 2;
+        cache.put("new", &2).unwrap();
+
+        assert_eq!(cache.get::<u32>("old"), None);
+        assert_eq!(cache.get::<u32>("new"), Some(2));
+
+        fs::remove_dir_all(&cache.config.path).unwrap();
+    }
+}
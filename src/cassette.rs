@@ -0,0 +1,207 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use {
+    anyhow::{Context, Result},
+    chrono::{DateTime, Utc},
+    log::error,
+    serde::{Deserialize, Serialize},
+    serde_json::{json, Value as JsonValue},
+};
+
+use crate::secrets::redact;
+
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    function: String,
+    params: JsonValue,
+    result: JsonValue,
+}
+
+/// A task run, recorded so replaying can run it again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Invocation {
+    pub task: String,
+    pub handler: String,
+    pub params: JsonValue,
+    pub started: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Call(Interaction),
+    Run(Invocation),
+}
+
+/// Records every task run and registered function call to a JSON lines file, or serves the
+/// results of a previous recording back instead of running anything.
+pub enum Cassette {
+    Record(BufWriter<File>),
+    Replay {
+        /// Recorded results by call, in the order they were made.
+        calls: HashMap<String, VecDeque<JsonValue>>,
+        /// Recorded task runs, in the order they started.
+        runs: Vec<Invocation>,
+    },
+}
+
+impl Cassette {
+    pub fn record(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("unable to create cassette {}", path.display()))?;
+        Ok(Cassette::Record(BufWriter::new(file)))
+    }
+
+    pub fn replay(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("unable to read cassette {}", path.display()))?;
+
+        let mut calls: HashMap<String, VecDeque<JsonValue>> = HashMap::new();
+        let mut runs = vec![];
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(line)
+                .with_context(|| format!("invalid interaction at {}:{}", path.display(), i + 1))?;
+            match entry {
+                Entry::Call(interaction) => calls
+                    .entry(call_key(&interaction.function, &interaction.params))
+                    .or_default()
+                    .push_back(interaction.result),
+                Entry::Run(invocation) => runs.push(invocation),
+            }
+        }
+
+        Ok(Cassette::Replay { calls, runs })
+    }
+
+    /// Takes the task runs to replay. `None` when recording.
+    pub fn take_runs(&mut self) -> Option<Vec<Invocation>> {
+        match self {
+            Cassette::Record(_) => None,
+            Cassette::Replay { runs, .. } => Some(std::mem::take(runs)),
+        }
+    }
+
+    /// Saves the start of a task run when recording.
+    pub fn record_run(&mut self, invocation: Invocation) {
+        if let Cassette::Record(writer) = self {
+            if let Err(e) = write_entry(writer, &Entry::Run(invocation)) {
+                error!("Unable to record task run: {}", e);
+            }
+        }
+    }
+
+    /// Runs `function` through the cassette. Recording calls it and saves the result, replaying
+    /// returns the next recorded result for the same function and params without calling it.
+    pub fn call(
+        &mut self,
+        function: &str,
+        params: JsonValue,
+        live: impl FnOnce(JsonValue) -> JsonValue,
+    ) -> JsonValue {
+        match self {
+            Cassette::Record(writer) => {
+                let result = live(params.clone());
+                let entry = Entry::Call(Interaction {
+                    function: function.to_string(),
+                    params,
+                    result: result.clone(),
+                });
+                if let Err(e) = write_entry(writer, &entry) {
+                    error!("Unable to record {} call: {}", function, e);
+                }
+                result
+            }
+            Cassette::Replay { calls, .. } => {
+                match calls
+                    .get_mut(&call_key(function, &params))
+                    .and_then(VecDeque::pop_front)
+                {
+                    Some(result) => result,
+                    None => {
+                        let message = format!("no recorded {} call with these params", function);
+                        error!("{}: {}", message, params);
+                        json!({ "error": message })
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Params come from Lua tables which serialize with sorted keys, so equal calls give equal keys.
+/// Secrets are redacted to match how they were recorded.
+fn call_key(function: &str, params: &JsonValue) -> String {
+    redact(&format!("{}\n{}", function, params)).into_owned()
+}
+
+fn write_entry(writer: &mut BufWriter<File>, entry: &Entry) -> Result<()> {
+    // Cassettes get shared and committed, so secrets never go in them.
+    let line = serde_json::to_string(entry)?;
+    writeln!(writer, "{}", redact(&line))?;
+    // Flush every call so a crashed run still leaves a usable cassette.
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("salient-cassette-{}", std::process::id()));
+
+        let started = "2026-10-19T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let invocation = Invocation {
+            task: String::from("Weather"),
+            handler: String::from("WeatherHandler"),
+            params: json!({ "city": "Chicago" }),
+            started,
+        };
+
+        let mut cassette = Cassette::record(&path).unwrap();
+        assert_eq!(cassette.take_runs(), None);
+        cassette.record_run(invocation.clone());
+        let mut calls = 0;
+        for _ in 0..2 {
+            cassette.call("http_get", json!({ "uri": "https://example.com" }), |_| {
+                calls += 1;
+                json!({ "call": calls })
+            });
+        }
+        cassette.call(
+            "percent_encode",
+            json!({ "input": "a b" }),
+            |_| json!({ "output": "a%20b" }),
+        );
+        drop(cassette);
+
+        let mut cassette = Cassette::replay(&path).unwrap();
+        assert_eq!(cassette.take_runs(), Some(vec![invocation]));
+        let live = |_| panic!("replay ran a live call");
+        assert_eq!(
+            cassette.call("percent_encode", json!({ "input": "a b" }), live),
+            json!({ "output": "a%20b" })
+        );
+        for expected in 1..=2 {
+            assert_eq!(
+                cassette.call("http_get", json!({ "uri": "https://example.com" }), live),
+                json!({ "call": expected })
+            );
+        }
+        assert!(cassette
+            .call("http_get", json!({ "uri": "https://example.com" }), live)
+            .get("error")
+            .is_some());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
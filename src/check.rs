@@ -0,0 +1,338 @@
+use std::{collections::HashSet, fs};
+
+use {
+    anyhow::{bail, Result},
+    chrono::Utc,
+    mlua::prelude::*,
+};
+
+use crate::{
+    config::{Config, Script},
+    secrets::SecretStore,
+    task_execution::TaskSchedule,
+};
+
+/// How many upcoming runs to print for each task.
+const UPCOMING_RUNS: usize = 3;
+
+/// Functions the service registers for scripts. Scripts are checked without them, so each is
+/// replaced with one returning `nil` in case a script calls it while loading.
+const FUNCTIONS: [&str; 10] = [
+    "transcribe",
+    "llm_eval",
+    "model_load",
+    "cache_stats",
+    "secret",
+    "schedule_task",
+    "cancel_task",
+    "http_get",
+    "percent_encode",
+    "json_to_lua",
+];
+
+/// The parts of `os` scripts can use while being checked. The rest can change the system.
+const OS_FUNCTIONS: [&str; 5] = ["clock", "date", "difftime", "getenv", "time"];
+
+/// Validates every script and task in the config without running anything, printing what each
+/// task's schedule resolves to. `problems` are those found loading the config, such as fragments
+/// that don't parse or an invalid model. They are reported along with any problems with scripts,
+/// tasks and secrets before failing.
+pub fn check(config: &Config, mut problems: Vec<String>) -> Result<()> {
+    println!("Config: {}", config.path.display());
+
+    let mut task_names = HashSet::new();
+
+    let secrets = match &config.secrets {
+        Some(secrets) => match SecretStore::load(secrets) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                problems.push(format!("unable to load secrets: {:#}", e));
+                None
+            }
+        },
+        None => None,
+    };
+
+    for script in &config.scripts {
+        println!("\nScript: {}", script.path.display());
+
+        for name in &script.secrets {
+            match (&config.secrets, &secrets) {
+                (None, _) => problems.push(format!(
+                    "script {} declares secret {} but no secrets are configured",
+                    script.path.display(),
+                    name
+                )),
+                (Some(_), Some(store)) if !store.contains(name) => problems.push(format!(
+                    "script {} declares secret {} which isn't in the secrets store",
+                    script.path.display(),
+                    name
+                )),
+                _ => {}
+            }
+        }
+
+        for task in &script.tasks {
+            if task.handler() == task.name {
+                println!("  Task: {} ({})", task.name, task.when);
+            } else {
+                println!(
+                    "  Task: {} -> {} ({})",
+                    task.name,
+                    task.handler(),
+                    task.when
+                );
+            }
+
+            if !task_names.insert(&task.name) {
+                problems.push(format!("task {} is defined more than once", task.name));
+            }
+
+            let timezone = task.timezone(&config.scheduling);
+            match TaskSchedule::new(&task.when, timezone) {
+                Ok(schedule) => {
+                    let mut upcoming = schedule.upcoming(Utc::now()).take(UPCOMING_RUNS).peekable();
+                    if upcoming.peek().is_none() {
+                        println!("    no runs left");
+                    }
+                    for next in upcoming {
+                        println!("    next: {}", next.with_timezone(&timezone));
+                    }
+                }
+                Err(e) => problems.push(format!(
+                    "task {} has an invalid schedule: {:#}",
+                    task.name, e
+                )),
+            }
+        }
+
+        match fs::read_to_string(&script.path) {
+            Ok(contents) => problems.extend(check_script(script, &contents)),
+            Err(e) => problems.push(format!(
+                "unable to read script {}: {}",
+                script.path.display(),
+                e
+            )),
+        }
+    }
+
+    if problems.is_empty() {
+        println!("\nNo problems found");
+        return Ok(());
+    }
+
+    println!("\nProblems:");
+    for problem in &problems {
+        println!("  - {}", problem);
+    }
+
+    bail!("found {} problem(s)", problems.len());
+}
+
+/// Loads the script in a throwaway, sandboxed Lua state and checks each task has `setup` and
+/// `execute`. The state has no `io`, only the harmless parts of `os`, and stubs for the registered
+/// functions, so loading a script can't touch files or reach the network.
+fn check_script(script: &Script, contents: &str) -> Vec<String> {
+    let lua = Lua::new();
+    let mut problems = vec![];
+
+    if let Err(e) = sandbox(&lua) {
+        problems.push(format!("unable to set up the Lua sandbox: {}", e));
+        return problems;
+    }
+
+    if let Some(dir) = script.path.parent().and_then(|dir| dir.to_str()) {
+        let set_path = lua
+            .globals()
+            .get::<_, LuaTable>("package")
+            .and_then(|package| {
+                let path: String = package.get("path")?;
+                package.set("path", format!("{};{}/?.lua", path, dir))
+            });
+        if let Err(e) = set_path {
+            problems.push(format!("unable to set package.path: {}", e));
+        }
+    }
+
+    if let Err(e) = lua
+        .load(contents)
+        .set_name(script.path.to_string_lossy())
+        .exec()
+    {
+        problems.push(format!(
+            "script {} failed to load: {}",
+            script.path.display(),
+            e
+        ));
+        return problems;
+    }
+
+    let mut checked = HashSet::new();
+    for task in &script.tasks {
+        if !checked.insert(task.handler()) {
+            continue;
+        }
+
+        let table = match lua.globals().get::<_, LuaValue>(task.handler()) {
+            Ok(LuaValue::Table(table)) => table,
+            _ => {
+                problems.push(format!(
+                    "script {} doesn't define a {} table for task {}",
+                    script.path.display(),
+                    task.handler(),
+                    task.name
+                ));
+                continue;
+            }
+        };
+
+        for method in ["setup", "execute"] {
+            if !matches!(table.get::<_, LuaValue>(method), Ok(LuaValue::Function(_))) {
+                problems.push(format!(
+                    "task {} has no {}.{} function in {}",
+                    task.name,
+                    task.handler(),
+                    method,
+                    script.path.display()
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+fn sandbox(lua: &Lua) -> LuaResult<()> {
+    let globals = lua.globals();
+
+    let os: LuaTable = globals.get("os")?;
+    let safe_os = lua.create_table()?;
+    for name in OS_FUNCTIONS {
+        safe_os.set(name, os.get::<_, LuaValue>(name)?)?;
+    }
+    globals.set("os", safe_os)?;
+    globals.set("io", LuaNil)?;
+
+    let stub = lua.create_function(|_, _: LuaMultiValue| Ok(()))?;
+    for name in FUNCTIONS {
+        globals.set(name, stub.clone())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::{Task, When};
+
+    #[test]
+    fn test_check_script() {
+        let script = Script {
+            path: "./scripts/test.lua".into(),
+            secrets: vec![],
+            tasks: vec![
+                Task {
+                    name: String::from("Complete"),
+                    handler: None,
+                    when: When::Cron(String::from("0 * * * * * *")),
+                    timezone: None,
+                    params: Default::default(),
+                    policy: Default::default(),
+                    unknown: Default::default(),
+                },
+                Task {
+                    name: String::from("CompleteHourly"),
+                    handler: Some(String::from("Complete")),
+                    when: When::Cron(String::from("0 0 * * * * *")),
+                    timezone: None,
+                    params: Default::default(),
+                    policy: Default::default(),
+                    unknown: Default::default(),
+                },
+                Task {
+                    name: String::from("NoExecute"),
+                    handler: None,
+                    when: When::Cron(String::from("0 * * * * * *")),
+                    timezone: None,
+                    params: Default::default(),
+                    policy: Default::default(),
+                    unknown: Default::default(),
+                },
+                Task {
+                    name: String::from("Missing"),
+                    handler: None,
+                    when: When::Cron(String::from("0 * * * * * *")),
+                    timezone: None,
+                    params: Default::default(),
+                    policy: Default::default(),
+                    unknown: Default::default(),
+                },
+            ],
+        };
+
+        let problems = check_script(
+            &script,
+            r#"
+Complete = {}
+function Complete.setup() end
+function Complete.execute() end
+
+NoExecute = {}
+function NoExecute.setup() end
+"#,
+        );
+
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("NoExecute.execute"));
+        assert!(problems[1].contains("Missing table"));
+    }
+
+    #[test]
+    fn test_check_script_sandbox() {
+        let script = Script {
+            path: "./scripts/test.lua".into(),
+            secrets: vec![],
+            tasks: vec![Task {
+                name: String::from("Sandboxed"),
+                handler: None,
+                when: When::Cron(String::from("0 * * * * * *")),
+                timezone: None,
+                params: Default::default(),
+                policy: Default::default(),
+                unknown: Default::default(),
+            }],
+        };
+
+        // Registered functions are stubbed, so calling one while loading doesn't fail.
+        let problems = check_script(
+            &script,
+            r#"
+Sandboxed = { started = os.time(), page = http_get({ url = "https://example.com" }) }
+function Sandboxed.setup() end
+function Sandboxed.execute() end
+"#,
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        for code in ["os.execute('true')", "io.open('/etc/passwd')"] {
+            let problems = check_script(&script, code);
+            assert_eq!(problems.len(), 1);
+            assert!(problems[0].contains("failed to load"));
+        }
+    }
+
+    #[test]
+    fn test_check_script_syntax_error() {
+        let script = Script {
+            path: "./scripts/test.lua".into(),
+            secrets: vec![],
+            tasks: vec![],
+        };
+
+        let problems = check_script(&script, "function Broken.setup(");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("failed to load"));
+    }
+}
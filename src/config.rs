@@ -1,67 +1,976 @@
 use std::{
+    env,
+    fmt::{Display, Error as FmtError, Formatter},
     fs,
     path::{Path, PathBuf},
 };
 
 use {
-    anyhow::{bail, Context, Result},
-    hf_hub::api::sync::ApiBuilder,
-    serde::{Deserialize, Serialize},
+    anyhow::{anyhow, bail, Context, Result},
+    chrono_tz::Tz,
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    serde_json::{Map, Value as JsonValue},
+    toml::{Table as TomlTable, Value as TomlValue},
 };
 
+use crate::hub;
+
 const CONFIG_LOCATIONS: [&str; 2] = ["./sailent.toml", "/etc/sailent/sailent.toml"];
+/// Directory next to the config whose `*.toml` files add more scripts.
+const FRAGMENT_DIR: &str = "conf.d";
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
+    /// File the config was loaded from, so reloads read the same one.
+    #[serde(skip)]
+    pub path: PathBuf,
     pub model: Model,
+    #[serde(default)]
+    pub hub: Hub,
+    pub transcription: Option<Transcription>,
+    pub cache: Option<ResponseCache>,
+    pub secrets: Option<Secrets>,
+    #[serde(default)]
+    pub scheduling: Scheduling,
     pub scripts: Vec<Script>,
 }
 
+/// A file in `conf.d`. Only scripts can be added this way.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fragment {
+    #[serde(default)]
+    scripts: Vec<Script>,
+}
+
 impl Config {
-    pub fn new() -> Result<Self> {
-        for path in CONFIG_LOCATIONS {
-            let path = Path::new(path);
-
-            if path.exists() {
-                return Ok(toml::from_str(&fs::read_to_string(
-                    path.to_str().unwrap(),
-                )?)?);
+    /// Loads `path`, or the first of `CONFIG_LOCATIONS` that exists, along with any fragments in
+    /// its `conf.d` directory.
+    pub fn new(path: Option<&Path>) -> Result<Self> {
+        let (config, problems) = Self::load(path)?;
+        if !problems.is_empty() {
+            bail!("{}", problems.join("\n"));
+        }
+
+        Ok(config)
+    }
+
+    /// Like `new`, but fragments that can't be read and an invalid model are returned as problems
+    /// alongside the config rather than failing, so they can all be reported at once. Only a config
+    /// file that can't be found, read or parsed is an error.
+    pub fn load(path: Option<&Path>) -> Result<(Self, Vec<String>)> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => CONFIG_LOCATIONS
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.exists())
+                .context("Couldn't find config")?,
+        };
+
+        let mut config: Config = read_toml(&path)?;
+        config.path = path.clone();
+        let mut problems = vec![];
+
+        match fragments(&path) {
+            Ok(fragment_paths) => {
+                for fragment_path in fragment_paths {
+                    match read_toml::<Fragment>(&fragment_path) {
+                        Ok(fragment) => config.scripts.extend(fragment.scripts),
+                        Err(e) => problems.push(format!("{:#}", e)),
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("{:#}", e)),
+        }
+
+        if let Err(e) = config.model.validate() {
+            problems.push(format!("invalid model in {}: {:#}", path.display(), e));
+        }
+
+        for script in &config.scripts {
+            for task in &script.tasks {
+                if let Err(e) = task.validate() {
+                    problems.push(format!("{} in {}", e, script.path.display()));
+                }
             }
         }
 
-        bail!("Couldn't find config");
+        Ok((config, problems))
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum Model {
-    Local { path: PathBuf },
-    HuggingFace { repo: String, model: String },
+/// The `conf.d` directory for the config at `path`.
+pub fn fragment_dir(path: &Path) -> PathBuf {
+    path.parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(FRAGMENT_DIR)
+}
+
+/// `conf.d/*.toml` beside `path` in name order, so fragments can be ordered with a numeric prefix.
+fn fragments(path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = fragment_dir(path);
+
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut fragments = vec![];
+    for entry in fs::read_dir(&dir).with_context(|| format!("unable to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            fragments.push(path);
+        }
+    }
+    fragments.sort();
+
+    Ok(fragments)
+}
+
+fn read_toml<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("unable to read {}", path.display()))?;
+    parse_toml(path, &contents, |name| env::var(name).ok())
+}
+
+/// Parses `contents` with `${NAME}` in string values replaced using `lookup`.
+fn parse_toml<T: DeserializeOwned>(
+    path: &Path,
+    contents: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<T> {
+    let located = |e: toml::de::Error| anyhow!("{}: {}", path.display(), e);
+
+    // Interpolated values lose their position, so check the shape against the raw text first to
+    // point errors at a line. Interpolation only ever replaces strings with strings.
+    toml::from_str::<T>(contents).map_err(located)?;
+
+    let mut value: TomlValue = toml::from_str(contents).map_err(located)?;
+    interpolate(&mut value, "", &lookup).map_err(|e| anyhow!("{}: {:#}", path.display(), e))?;
+    value.try_into().map_err(located)
+}
+
+fn interpolate(
+    value: &mut TomlValue,
+    key: &str,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    match value {
+        TomlValue::String(string) => {
+            *string = expand_env(string, lookup).with_context(|| key.to_string())?;
+        }
+        TomlValue::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                interpolate(value, &format!("{}[{}]", key, i), lookup)?;
+            }
+        }
+        TomlValue::Table(table) => {
+            for (name, value) in table.iter_mut() {
+                let key = if key.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", key, name)
+                };
+                interpolate(value, &key, lookup)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Replaces `${NAME}` with the variable's value. `$${` is a literal `${`.
+fn expand_env(value: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').context("unclosed `${`")?;
+            let name = &after[..end];
+            let env_value = lookup(name)
+                .with_context(|| format!("environment variable {} is not set", name))?;
+            expanded.push_str(&env_value);
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Model {
+    #[serde(flatten)]
+    pub source: ModelSource,
+    /// Defer loading the model until the first evaluation instead of at startup.
+    #[serde(default)]
+    pub lazy: bool,
+    /// Seconds without an evaluation before the model is unloaded. It is loaded again on next use.
+    pub idle_unload: Option<u64>,
+    #[serde(default)]
+    pub params: ModelParams,
+    /// LoRA adapters loaded alongside the model and enabled per call by name.
+    #[serde(default)]
+    pub adapters: Vec<Adapter>,
+    /// Smaller model sharing the vocabulary, used to draft tokens for speculative decoding.
+    pub draft: Option<Draft>,
+    /// Delimiters of the thinking emitted by reasoning models, split out of the response content.
+    pub reasoning: Option<Reasoning>,
+    /// Vision projector for models that accept images.
+    pub mmproj: Option<ModelSource>,
 }
 
 impl Model {
-    pub fn get_or_load(&self) -> Result<PathBuf> {
+    pub fn get_or_load(&self, hub: &Hub) -> Result<PathBuf> {
+        self.source.get_or_load(hub)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.params.validate()?;
+
+        for (i, adapter) in self.adapters.iter().enumerate() {
+            if self.adapters[..i].iter().any(|a| a.name == adapter.name) {
+                bail!("adapter {} is defined more than once", adapter.name);
+            }
+        }
+
+        if let Some(draft) = &self.draft {
+            if draft.length == 0 || draft.length >= self.params.n_batch as usize {
+                bail!(
+                    "draft.length must be between 1 and n_batch - 1 ({}), got {}",
+                    self.params.n_batch - 1,
+                    draft.length
+                );
+            }
+        }
+
+        if let Some(reasoning) = &self.reasoning {
+            if reasoning.start.is_empty() || reasoning.end.is_empty() {
+                bail!("reasoning delimiters can't be empty");
+            }
+
+            if reasoning.budget == Some(0) {
+                bail!("reasoning.budget must be greater than 0");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether switching from `other` to this model needs the weights loaded again. Context
+    /// parameters are applied per evaluation so only the source and load parameters matter.
+    pub fn needs_reload(&self, other: &Model) -> bool {
+        self.source != other.source
+            || self.adapters != other.adapters
+            || self.draft != other.draft
+            || self.mmproj != other.mmproj
+            || self.params.n_gpu_layers != other.params.n_gpu_layers
+            || self.params.use_mmap != other.params.use_mmap
+            || self.params.use_mlock != other.params.use_mlock
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Draft {
+    #[serde(flatten)]
+    pub source: ModelSource,
+    /// Tokens drafted before the main model checks them.
+    #[serde(default = "Draft::default_length")]
+    pub length: usize,
+}
+
+impl Draft {
+    fn default_length() -> usize {
+        8
+    }
+}
+
+/// Whisper model used by `transcribe`. Runs on the CPU so it can share the machine with the LLM.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transcription {
+    #[serde(flatten)]
+    pub source: ModelSource,
+    /// Spoken language, e.g. `en`, or `auto` to detect it. Defaults to English.
+    pub language: Option<String>,
+    pub n_threads: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reasoning {
+    #[serde(default = "Reasoning::default_start")]
+    pub start: String,
+    #[serde(default = "Reasoning::default_end")]
+    pub end: String,
+    /// Tokens the model may spend reasoning before the end delimiter is forced.
+    pub budget: Option<usize>,
+}
+
+impl Reasoning {
+    fn default_start() -> String {
+        String::from("<think>")
+    }
+
+    fn default_end() -> String {
+        String::from("</think>")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Adapter {
+    pub name: String,
+    pub path: PathBuf,
+    /// Strength the adapter is applied with when a call doesn't give one.
+    #[serde(default = "Adapter::default_scale")]
+    pub scale: f32,
+}
+
+impl Adapter {
+    fn default_scale() -> f32 {
+        1.0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelParams {
+    pub n_ctx: u32,
+    pub n_batch: u32,
+    pub n_ubatch: Option<u32>,
+    pub n_threads: Option<u32>,
+    pub use_mmap: bool,
+    pub use_mlock: bool,
+    pub n_gpu_layers: u32,
+    pub rope_scaling: Option<RopeScaling>,
+}
+
+impl Default for ModelParams {
+    fn default() -> Self {
+        Self {
+            n_ctx: 1024 * 15,
+            n_batch: 1024 * 15,
+            n_ubatch: None,
+            n_threads: None,
+            use_mmap: true,
+            use_mlock: false,
+            n_gpu_layers: 0,
+            rope_scaling: None,
+        }
+    }
+}
+
+impl ModelParams {
+    pub fn validate(&self) -> Result<()> {
+        if self.n_ctx == 0 {
+            bail!("n_ctx must be greater than 0");
+        }
+
+        if self.n_batch == 0 || self.n_batch > self.n_ctx {
+            bail!(
+                "n_batch must be between 1 and n_ctx ({}), got {}",
+                self.n_ctx,
+                self.n_batch
+            );
+        }
+
+        if let Some(n_ubatch) = self.n_ubatch {
+            if n_ubatch == 0 || n_ubatch > self.n_batch {
+                bail!(
+                    "n_ubatch must be between 1 and n_batch ({}), got {}",
+                    self.n_batch,
+                    n_ubatch
+                );
+            }
+        }
+
+        if self.n_threads == Some(0) {
+            bail!("n_threads must be greater than 0");
+        }
+
+        if self.use_mlock && !self.use_mmap {
+            bail!("use_mlock requires use_mmap");
+        }
+
+        if let Some(rope_scaling) = &self.rope_scaling {
+            rope_scaling.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RopeScaling {
+    #[serde(rename = "type")]
+    pub scaling_type: RopeScalingKind,
+    pub freq_base: Option<f32>,
+    pub freq_scale: Option<f32>,
+}
+
+impl RopeScaling {
+    fn validate(&self) -> Result<()> {
+        if self.freq_base.is_some_and(|base| base <= 0.0) {
+            bail!("rope_scaling.freq_base must be greater than 0");
+        }
+
+        if self.freq_scale.is_some_and(|scale| scale <= 0.0) {
+            bail!("rope_scaling.freq_scale must be greater than 0");
+        }
+
+        if self.scaling_type == RopeScalingKind::None
+            && (self.freq_base.is_some() || self.freq_scale.is_some())
+        {
+            bail!("rope_scaling frequencies have no effect with type \"none\"");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RopeScalingKind {
+    None,
+    Linear,
+    Yarn,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ModelSource {
+    Local {
+        path: PathBuf,
+    },
+    HuggingFace {
+        repo: String,
+        model: String,
+        /// Branch, tag or commit to fetch from. Defaults to `main`.
+        revision: Option<String>,
+    },
+}
+
+impl ModelSource {
+    pub fn get_or_load(&self, hub: &Hub) -> Result<PathBuf> {
         match self {
-            Model::Local { path } => Ok(path.clone()),
-            Model::HuggingFace { model, repo } => ApiBuilder::new()
-                .with_progress(true)
-                .build()
-                .with_context(|| "unable to create huggingface api")?
-                .model(repo.clone())
-                .get(model)
-                .with_context(|| "unable to download model"),
+            ModelSource::Local { path } => Ok(path.clone()),
+            ModelSource::HuggingFace {
+                repo,
+                model,
+                revision,
+            } => hub::fetch(hub, repo, model, revision.as_deref())
+                .with_context(|| format!("unable to get {} from {}", model, repo)),
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Hub {
+    /// Defaults to `$HF_HOME/hub` or `~/.cache/huggingface/hub`.
+    pub cache_dir: Option<PathBuf>,
+    /// Alternative to huggingface.co such as a local mirror. Defaults to `$HF_ENDPOINT`.
+    pub endpoint: Option<String>,
+    /// Only use files already in the cache and fail if they're missing.
+    pub offline: bool,
+    /// Check downloads against the SHA-256 published by the hub.
+    pub verify: bool,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self {
+            cache_dir: None,
+            endpoint: None,
+            offline: false,
+            verify: true,
+        }
+    }
+}
+
+/// On-disk cache of `llm_eval` responses. Only enabled when the section is present.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseCache {
+    pub path: PathBuf,
+    /// Seconds before an entry expires. Entries never expire if unset.
+    pub ttl: Option<u64>,
+    /// Total size of all entries in bytes. The least recently used are evicted past this.
+    pub max_size: u64,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("./cache"),
+            ttl: None,
+            max_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Where secrets for scripts are read from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Secrets {
+    /// `NAME=value` lines.
+    EnvFile { path: PathBuf },
+    /// A TOML table of names to values, readable only by its owner.
+    File { path: PathBuf },
+    /// An env file encrypted with age to an X25519 identity kept in `identity`.
+    Encrypted { path: PathBuf, identity: PathBuf },
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Script {
     pub path: PathBuf,
+    /// Names of the secrets the script may read.
+    #[serde(default)]
+    pub secrets: Vec<String>,
     pub tasks: Vec<Task>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Task {
     pub name: String,
-    pub cron: String,
+    /// Lua table with the task's `setup` and `execute`, defaulting to `name`. Several tasks can
+    /// share a handler to run it on different schedules or with different params.
+    pub handler: Option<String>,
+    #[serde(flatten)]
+    pub when: When,
+    /// IANA name of the timezone the schedule is in, overriding the global `timezone`.
+    pub timezone: Option<Tz>,
+    /// Passed to the handler's `execute` on every run.
+    #[serde(default)]
+    pub params: Map<String, JsonValue>,
+    #[serde(flatten)]
+    pub policy: RunPolicy,
+    /// Keys that aren't task settings. Flattening turns off serde's check for unknown fields, so
+    /// they are collected here and reported by `validate`.
+    #[serde(flatten)]
+    pub unknown: TomlTable,
+}
+
+impl Task {
+    /// Rejects keys that aren't task settings, which are most likely misspelled.
+    pub fn validate(&self) -> Result<()> {
+        if !self.unknown.is_empty() {
+            bail!(
+                "task {} has unknown setting(s) {}",
+                self.name,
+                self.unknown.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn handler(&self) -> &str {
+        self.handler.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The timezone to evaluate the schedule in, which is UTC unless one is configured.
+    pub fn timezone(&self, scheduling: &Scheduling) -> Tz {
+        self.timezone.or(scheduling.timezone).unwrap_or(Tz::UTC)
+    }
+}
+
+/// When a task runs, given by one of `cron`, `every` or `at`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum When {
+    /// Seven-field cron expression.
+    Cron(String),
+    /// Interval after the last run such as `15m` or `1h30m`, or a phrase such as
+    /// `weekdays at 08:30`.
+    Every(String),
+    /// Single run at a local date and time such as `2026-11-01T09:00`.
+    At(String),
+}
+
+impl Display for When {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            When::Cron(cron) => write!(formatter, "{}", cron),
+            When::Every(every) => write!(formatter, "every {}", every),
+            When::At(at) => write!(formatter, "at {}", at),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scheduling {
+    /// IANA name of the timezone task schedules are in. Defaults to UTC.
+    pub timezone: Option<Tz>,
+    /// File keeping when each task last ran, so runs missed while the service was down can be
+    /// caught up.
+    pub state: PathBuf,
+    /// File keeping jobs from `schedule_task` and runs that haven't finished, so they carry on
+    /// after a restart.
+    pub jobs: PathBuf,
+}
+
+impl Default for Scheduling {
+    fn default() -> Self {
+        Self {
+            timezone: None,
+            state: PathBuf::from("./schedule.json"),
+            jobs: PathBuf::from("./jobs.json"),
+        }
+    }
+}
+
+/// How a task's runs are limited and retried.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RunPolicy {
+    /// Seconds `execute` may run before it is stopped and counted as failed.
+    pub timeout: Option<u64>,
+    /// Times a failed run is tried again.
+    pub retries: u32,
+    /// Seconds before the first retry. Each retry after waits twice as long, with jitter.
+    pub retry_delay: u64,
+    pub concurrency: Concurrency,
+    /// Seconds a run may take from when it was due, including retries, before it is aborted.
+    pub max_runtime: Option<u64>,
+    pub catch_up: CatchUp,
+}
+
+impl Default for RunPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            retries: 0,
+            retry_delay: 1,
+            concurrency: Concurrency::Skip,
+            max_runtime: None,
+            catch_up: CatchUp::Skip,
+        }
+    }
+}
+
+/// What happens to runs that were due while the service was down or the machine was asleep.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUp {
+    /// Leave them and wait until the task is next due.
+    #[default]
+    Skip,
+    /// Run the task once for all of them.
+    RunOnce,
+    /// Run the task once for each of them, one after another.
+    RunAll,
+}
+
+/// What happens when a task is due while its previous run is still going.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Concurrency {
+    /// Don't run it this time.
+    #[default]
+    Skip,
+    /// Run it once the previous run finishes.
+    Queue,
+    /// Start it straight away. Runs share one Lua state, so `execute` still runs one at a time.
+    Parallel,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_params_are_valid() {
+        ModelParams::default().validate().unwrap();
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let params = vec![
+            ModelParams {
+                n_ctx: 0,
+                ..Default::default()
+            },
+            ModelParams {
+                n_ctx: 2048,
+                n_batch: 4096,
+                ..Default::default()
+            },
+            ModelParams {
+                n_ubatch: Some(1024 * 16),
+                ..Default::default()
+            },
+            ModelParams {
+                use_mmap: false,
+                use_mlock: true,
+                ..Default::default()
+            },
+            ModelParams {
+                rope_scaling: Some(RopeScaling {
+                    scaling_type: RopeScalingKind::None,
+                    freq_base: None,
+                    freq_scale: Some(0.5),
+                }),
+                ..Default::default()
+            },
+        ];
+
+        for params in params {
+            if params.validate().is_ok() {
+                panic!("Params should have failed validation: {:?}", params);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_model_params() {
+        let model: Model = toml::from_str(
+            r#"
+[params]
+n_ctx = 8192
+n_batch = 512
+n_gpu_layers = 33
+
+[params.rope_scaling]
+type = "yarn"
+freq_scale = 0.25
+
+[HuggingFace]
+repo = "QuantFactory/dolphin-2.9-llama3-8b-GGUF"
+model = "dolphin-2.9-llama3-8b.Q4_0.gguf"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(model.params.n_ctx, 8192);
+        assert_eq!(model.params.n_batch, 512);
+        assert_eq!(model.params.n_gpu_layers, 33);
+        assert!(model.params.use_mmap);
+        assert_eq!(
+            model.params.rope_scaling.as_ref().unwrap().scaling_type,
+            RopeScalingKind::Yarn
+        );
+        model.params.validate().unwrap();
+    }
+
+    #[test]
+    fn test_parse_mmproj() {
+        let model: Model = toml::from_str(
+            r#"
+[Local]
+path = "/models/llava-v1.6-mistral-7b.Q4_K_M.gguf"
+
+[mmproj.HuggingFace]
+repo = "cjpais/llava-1.6-mistral-7b-gguf"
+model = "mmproj-model-f16.gguf"
+"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            &model.mmproj,
+            Some(ModelSource::HuggingFace { model, .. }) if model == "mmproj-model-f16.gguf"
+        ));
+
+        let without = Model {
+            mmproj: None,
+            ..model.clone()
+        };
+        assert!(model.needs_reload(&without));
+        assert!(!model.needs_reload(&model.clone()));
+    }
+
+    #[test]
+    fn test_parse_task_params() {
+        let script: Script = toml::from_str(
+            r#"
+path = "./scripts/weather.lua"
+
+[[tasks]]
+name = "WeatherHome"
+handler = "Weather"
+cron = "0 0 7 * * * *"
+params = { location = "Ruston, Louisiana", days = 3 }
+retries = 3
+concurrency = "queue"
+timezone = "America/Chicago"
+catch_up = "run_once"
+
+[[tasks]]
+name = "Weather"
+cron = "0 0 8 * * * *"
+
+[[tasks]]
+name = "Poll"
+handler = "Weather"
+every = "15m"
+
+[[tasks]]
+name = "Reminder"
+handler = "Weather"
+at = "2026-11-01T09:00"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            script.tasks[0].when,
+            When::Cron(String::from("0 0 7 * * * *"))
+        );
+        assert_eq!(script.tasks[2].when, When::Every(String::from("15m")));
+        assert_eq!(
+            script.tasks[3].when,
+            When::At(String::from("2026-11-01T09:00"))
+        );
+        assert_eq!(script.tasks[2].policy, RunPolicy::default());
+        assert_eq!(script.tasks[0].handler(), "Weather");
+        assert_eq!(script.tasks[0].params["location"], "Ruston, Louisiana");
+        assert_eq!(script.tasks[0].params["days"], 3);
+        assert_eq!(script.tasks[0].policy.retries, 3);
+        assert_eq!(script.tasks[0].policy.concurrency, Concurrency::Queue);
+        assert_eq!(script.tasks[0].policy.catch_up, CatchUp::RunOnce);
+
+        let scheduling = Scheduling {
+            timezone: Some(Tz::Europe__London),
+            ..Default::default()
+        };
+        assert_eq!(script.tasks[0].timezone(&scheduling), Tz::America__Chicago);
+        assert_eq!(script.tasks[1].timezone(&scheduling), Tz::Europe__London);
+        assert_eq!(script.tasks[1].timezone(&Scheduling::default()), Tz::UTC);
+        assert_eq!(script.tasks[1].handler(), "Weather");
+        assert!(script.tasks[1].params.is_empty());
+        assert_eq!(script.tasks[1].policy, RunPolicy::default());
+        assert!(script.tasks.iter().all(|task| task.validate().is_ok()));
+    }
+
+    #[test]
+    fn test_unknown_task_settings() {
+        let script: Script = toml::from_str(
+            r#"
+path = "./scripts/weather.lua"
+
+[[tasks]]
+name = "Weather"
+cron = "0 0 7 * * * *"
+retry = 3
+max_run_time = 60
+"#,
+        )
+        .unwrap();
+
+        let error = script.tasks[0].validate().unwrap_err().to_string();
+        assert!(error.contains("max_run_time, retry"), "{}", error);
+        assert_eq!(script.tasks[0].policy, RunPolicy::default());
+    }
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "MODEL_DIR" => Some(String::from("/models")),
+            "REPO" => Some(String::from("QuantFactory/dolphin-2.9-llama3-8b-GGUF")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_env() {
+        assert_eq!(
+            expand_env("${MODEL_DIR}/model.gguf", &lookup).unwrap(),
+            "/models/model.gguf"
+        );
+        assert_eq!(
+            expand_env("$5 and $${MODEL_DIR}", &lookup).unwrap(),
+            "$5 and ${MODEL_DIR}"
+        );
+        assert!(expand_env("${MISSING}", &lookup).is_err());
+        assert!(expand_env("${MODEL_DIR", &lookup).is_err());
+    }
+
+    #[test]
+    fn test_interpolate_config() {
+        let path = Path::new("sailent.toml");
+        let contents = r#"
+[model.HuggingFace]
+repo = "${REPO}"
+model = "dolphin-2.9-llama3-8b.Q4_0.gguf"
+
+[[model.adapters]]
+name = "tone"
+path = "${MODEL_DIR}/tone.gguf"
+
+[[scripts]]
+path = "./scripts/test.lua"
+tasks = []
+"#;
+
+        let config: Config = parse_toml(path, contents, lookup).unwrap();
+        assert_eq!(
+            config.model.adapters[0].path,
+            PathBuf::from("/models/tone.gguf")
+        );
+        assert!(matches!(
+            config.model.source,
+            ModelSource::HuggingFace { repo, .. } if repo == "QuantFactory/dolphin-2.9-llama3-8b-GGUF"
+        ));
+
+        let error = parse_toml::<Config>(path, &contents.replace("REPO", "NOPE"), lookup)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("sailent.toml"));
+        assert!(error.contains("NOPE"));
+        assert!(error.contains("model.HuggingFace.repo"));
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let contents = "[model.Local]\npath = \"model.gguf\"\n\n[[scripts]]\npath = 5\n";
+        let error = parse_toml::<Config>(Path::new("conf.d/10-news.toml"), contents, lookup)
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error.starts_with("conf.d/10-news.toml: "));
+        assert!(error.contains("line 5"));
+    }
+
+    #[test]
+    fn test_load_problems() {
+        let dir = std::env::temp_dir().join(format!("salient-config-{}", std::process::id()));
+        fs::create_dir_all(dir.join(FRAGMENT_DIR)).unwrap();
+        let path = dir.join("sailent.toml");
+        fs::write(
+            &path,
+            "scripts = []\n\n[model.Local]\npath = \"model.gguf\"\n\n[model.params]\nn_ctx = 0\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(FRAGMENT_DIR).join("10-news.toml"),
+            "[[scripts]]\npath = 5\n",
+        )
+        .unwrap();
+        fs::write(dir.join(FRAGMENT_DIR).join("20-mail.toml"), "[[scripts]\n").unwrap();
+        fs::write(
+            dir.join(FRAGMENT_DIR).join("30-feeds.toml"),
+            "[[scripts]]\npath = \"feeds.lua\"\ntasks = []\n",
+        )
+        .unwrap();
+
+        let (config, problems) = Config::load(Some(&path)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Every bad fragment and the invalid model are reported, and the good fragment still loads.
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].contains("10-news.toml"));
+        assert!(problems[1].contains("20-mail.toml"));
+        assert!(problems[2].contains("n_ctx"));
+        assert_eq!(config.scripts.len(), 1);
+    }
 }
@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+const MAGIC: &[u8; 4] = b"GGUF";
+
+/// Keys that identify special tokens by id into `tokenizer.ggml.tokens`.
+pub const SPECIAL_TOKEN_KEYS: [(&str, &str); 6] = [
+    ("bos", "tokenizer.ggml.bos_token_id"),
+    ("eos", "tokenizer.ggml.eos_token_id"),
+    ("eot", "tokenizer.ggml.eot_token_id"),
+    ("unknown", "tokenizer.ggml.unknown_token_id"),
+    ("padding", "tokenizer.ggml.padding_token_id"),
+    ("separator", "tokenizer.ggml.seperator_token_id"),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum MetaValue {
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<MetaValue>),
+}
+
+impl MetaValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            MetaValue::UInt(value) => Some(*value),
+            MetaValue::Int(value) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MetaValue::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Key/value metadata from the header of a GGUF file. Tensor data is never read so this is cheap
+/// even for very large models.
+pub struct Metadata {
+    pub version: u32,
+    pub tensor_count: u64,
+    values: HashMap<String, MetaValue>,
+}
+
+impl Metadata {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+        Self::from_reader(&mut BufReader::new(file))
+            .with_context(|| format!("unable to read GGUF metadata from {}", path.display()))
+    }
+
+    fn from_reader(reader: &mut impl Read) -> Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a GGUF file");
+        }
+
+        let version = read_u32(reader)?;
+        if version < 2 {
+            bail!("unsupported GGUF version {}", version);
+        }
+
+        let tensor_count = read_u64(reader)?;
+        let kv_count = read_u64(reader)?;
+
+        let mut values = HashMap::new();
+        for _ in 0..kv_count {
+            let key = read_string(reader)?;
+            let value_type = read_u32(reader)?;
+            let value = read_value(reader, value_type)?;
+            values.insert(key, value);
+        }
+
+        Ok(Self {
+            version,
+            tensor_count,
+            values,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&MetaValue> {
+        self.values.get(key)
+    }
+
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture")?.as_str()
+    }
+
+    pub fn context_length(&self) -> Option<u64> {
+        self.get(&format!("{}.context_length", self.architecture()?))?
+            .as_u64()
+    }
+
+    pub fn quantization(&self) -> Option<&'static str> {
+        file_type_name(self.get("general.file_type")?.as_u64()?)
+    }
+
+    pub fn chat_template(&self) -> Option<&str> {
+        self.get("tokenizer.chat_template")?.as_str()
+    }
+
+    /// Text of the token with the given id in the vocabulary.
+    pub fn token(&self, id: u64) -> Option<&str> {
+        match self.get("tokenizer.ggml.tokens")? {
+            MetaValue::Array(tokens) => tokens.get(usize::try_from(id).ok()?)?.as_str(),
+            _ => None,
+        }
+    }
+}
+
+/// Names for `general.file_type`, following `llama_ftype` in llama.cpp.
+fn file_type_name(file_type: u64) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        _ => return None,
+    })
+}
+
+fn read_value(reader: &mut impl Read, value_type: u32) -> Result<MetaValue> {
+    Ok(match value_type {
+        0 => MetaValue::UInt(read_bytes::<1>(reader)?[0] as u64),
+        1 => MetaValue::Int(i8::from_le_bytes(read_bytes(reader)?) as i64),
+        2 => MetaValue::UInt(u16::from_le_bytes(read_bytes(reader)?) as u64),
+        3 => MetaValue::Int(i16::from_le_bytes(read_bytes(reader)?) as i64),
+        4 => MetaValue::UInt(read_u32(reader)? as u64),
+        5 => MetaValue::Int(i32::from_le_bytes(read_bytes(reader)?) as i64),
+        6 => MetaValue::Float(f32::from_le_bytes(read_bytes(reader)?) as f64),
+        7 => MetaValue::Bool(read_bytes::<1>(reader)?[0] != 0),
+        8 => MetaValue::Str(read_string(reader)?),
+        9 => {
+            let item_type = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            let mut items = Vec::with_capacity(len.min(1 << 20) as usize);
+            for _ in 0..len {
+                items.push(read_value(reader, item_type)?);
+            }
+            MetaValue::Array(items)
+        }
+        10 => MetaValue::UInt(read_u64(reader)?),
+        11 => MetaValue::Int(i64::from_le_bytes(read_bytes(reader)?)),
+        12 => MetaValue::Float(f64::from_le_bytes(read_bytes(reader)?)),
+        _ => bail!("unknown metadata value type {}", value_type),
+    })
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String> {
+    let len = read_u64(reader)?;
+    let mut buf = vec![];
+    reader.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        bail!("unexpected end of file reading string");
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_string(buf: &mut Vec<u8>, value: &str) {
+        buf.extend((value.len() as u64).to_le_bytes());
+        buf.extend(value.as_bytes());
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let mut buf = vec![];
+        buf.extend(MAGIC);
+        buf.extend(3u32.to_le_bytes());
+        buf.extend(0u64.to_le_bytes());
+        buf.extend(5u64.to_le_bytes());
+
+        push_string(&mut buf, "general.architecture");
+        buf.extend(8u32.to_le_bytes());
+        push_string(&mut buf, "llama");
+
+        push_string(&mut buf, "llama.context_length");
+        buf.extend(4u32.to_le_bytes());
+        buf.extend(8192u32.to_le_bytes());
+
+        push_string(&mut buf, "general.file_type");
+        buf.extend(4u32.to_le_bytes());
+        buf.extend(15u32.to_le_bytes());
+
+        push_string(&mut buf, "tokenizer.ggml.tokens");
+        buf.extend(9u32.to_le_bytes());
+        buf.extend(8u32.to_le_bytes());
+        buf.extend(2u64.to_le_bytes());
+        push_string(&mut buf, "<s>");
+        push_string(&mut buf, "</s>");
+
+        push_string(&mut buf, "tokenizer.ggml.eos_token_id");
+        buf.extend(4u32.to_le_bytes());
+        buf.extend(1u32.to_le_bytes());
+
+        let metadata = Metadata::from_reader(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(metadata.architecture(), Some("llama"));
+        assert_eq!(metadata.context_length(), Some(8192));
+        assert_eq!(metadata.quantization(), Some("Q4_K_M"));
+        assert_eq!(metadata.token(1), Some("</s>"));
+        assert_eq!(
+            metadata.get("tokenizer.ggml.eos_token_id"),
+            Some(&MetaValue::UInt(1))
+        );
+        assert_eq!(metadata.chat_template(), None);
+    }
+
+    #[test]
+    fn test_not_gguf() {
+        assert!(Metadata::from_reader(&mut b"GGML\x03\x00\x00\x00".as_slice()).is_err());
+    }
+}
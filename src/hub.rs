@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use {
+    anyhow::{bail, Context, Result},
+    hf_hub::{
+        api::sync::{ApiBuilder, ApiRepo},
+        Cache, Repo, RepoType,
+    },
+    log::{info, warn},
+    serde::Deserialize,
+    sha2::{Digest, Sha256},
+};
+
+use crate::config::Hub;
+
+const DEFAULT_REVISION: &str = "main";
+
+#[derive(Deserialize)]
+struct BlobInfo {
+    siblings: Vec<BlobSibling>,
+}
+
+#[derive(Deserialize)]
+struct BlobSibling {
+    rfilename: String,
+    lfs: Option<Lfs>,
+}
+
+#[derive(Deserialize)]
+struct Lfs {
+    sha256: String,
+}
+
+impl Hub {
+    pub fn cache(&self) -> Cache {
+        match &self.cache_dir {
+            Some(cache_dir) => Cache::new(cache_dir.clone()),
+            None => Cache::from_env(),
+        }
+    }
+}
+
+/// Returns the local path of `model` from `repo`, downloading it into the cache if needed. For
+/// split GGUFs every shard is fetched and the path of the first is returned, which is what
+/// llama.cpp expects to be given.
+pub fn fetch(hub: &Hub, repo: &str, model: &str, revision: Option<&str>) -> Result<PathBuf> {
+    let repo = Repo::with_revision(
+        repo.to_string(),
+        RepoType::Model,
+        revision.unwrap_or(DEFAULT_REVISION).to_string(),
+    );
+    let cache = hub.cache().repo(repo.clone());
+    let files = shard_files(model);
+
+    if hub.offline {
+        let mut paths = files
+            .iter()
+            .map(|file| {
+                cache
+                    .get(file)
+                    .with_context(|| format!("{} is not cached and offline mode is enabled", file))
+            })
+            .collect::<Result<Vec<PathBuf>>>()?;
+        return Ok(paths.remove(0));
+    }
+
+    let mut builder = ApiBuilder::from_env().with_progress(true);
+    if let Some(cache_dir) = &hub.cache_dir {
+        builder = builder.with_cache_dir(cache_dir.clone());
+    }
+    if let Some(endpoint) = &hub.endpoint {
+        builder = builder.with_endpoint(endpoint.clone());
+    }
+    let api = builder
+        .build()
+        .with_context(|| "unable to create huggingface api")?
+        .repo(repo);
+
+    let checksums = if hub.verify {
+        checksums(&api)?
+    } else {
+        HashMap::new()
+    };
+
+    let mut paths = vec![];
+    for file in &files {
+        let path = match cache.get(file) {
+            Some(path) if !is_stale(&path, checksums.get(file)) => path,
+            _ => {
+                info!("Downloading {}", file);
+                let path = api
+                    .download(file)
+                    .with_context(|| format!("unable to download {}", file))?;
+                if let Some(expected) = checksums.get(file) {
+                    verify_sha256(&path, expected)?;
+                }
+                path
+            }
+        };
+        paths.push(path);
+    }
+
+    Ok(paths.remove(0))
+}
+
+/// Expands `name-00001-of-00003.gguf` into the names of all three shards. Anything that doesn't
+/// follow the split naming is returned as is.
+pub fn shard_files(model: &str) -> Vec<String> {
+    let split = model
+        .strip_suffix(".gguf")
+        .and_then(|stem| stem.rsplit_once("-of-"))
+        .and_then(|(head, total)| {
+            let (base, index) = head.rsplit_once('-')?;
+            let is_shard_number = |s: &str| s.len() == 5 && s.chars().all(|c| c.is_ascii_digit());
+            if !is_shard_number(index) || !is_shard_number(total) {
+                return None;
+            }
+            Some((base, total, total.parse::<u32>().ok()?))
+        });
+
+    match split {
+        Some((base, total, count)) => (1..=count)
+            .map(|i| format!("{}-{:05}-of-{}.gguf", base, i, total))
+            .collect(),
+        None => vec![model.to_string()],
+    }
+}
+
+/// Fetches the SHA-256 of every LFS file in the repo at its revision. Small non-LFS files have no
+/// published hash and are skipped.
+fn checksums(api: &ApiRepo) -> Result<HashMap<String, String>> {
+    let info: BlobInfo = api
+        .info_request()
+        .query("blobs", "true")
+        .call()
+        .with_context(|| "unable to fetch repo metadata")?
+        .into_json()?;
+
+    Ok(info
+        .siblings
+        .into_iter()
+        .filter_map(|sibling| Some((sibling.rfilename, sibling.lfs?.sha256)))
+        .collect())
+}
+
+/// The hub cache stores LFS files as blobs named by their SHA-256, so a cached file whose blob
+/// name doesn't match the published hash is from an older revision and needs fetching again.
+fn is_stale(path: &Path, expected: Option<&String>) -> bool {
+    let Some(expected) = expected else {
+        return false;
+    };
+
+    let blob = fs::canonicalize(path).ok();
+    let blob_name = blob.as_ref().and_then(|blob| blob.file_name());
+    if blob_name.is_some_and(|name| name == expected.as_str()) {
+        return false;
+    }
+
+    warn!(
+        "Cached {} doesn't match the hub checksum, fetching again",
+        path.display()
+    );
+    true
+}
+
+pub fn verify_sha256(path: &Path, expected: &str) -> Result<()> {
+    let mut hasher = Sha256::new();
+    let mut file =
+        File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+    io::copy(&mut file, &mut hasher)?;
+    let actual = format!("{:x}", hasher.finalize());
+
+    if actual != expected {
+        let _ = fs::remove_file(fs::canonicalize(path)?);
+        bail!(
+            "checksum mismatch for {}: expected {}, got {}",
+            path.display(),
+            expected,
+            actual
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shard_files() {
+        assert_eq!(
+            shard_files("Qwen2-72B-Instruct-v0.1.Q4_K_M-00001-of-00003.gguf"),
+            vec![
+                "Qwen2-72B-Instruct-v0.1.Q4_K_M-00001-of-00003.gguf",
+                "Qwen2-72B-Instruct-v0.1.Q4_K_M-00002-of-00003.gguf",
+                "Qwen2-72B-Instruct-v0.1.Q4_K_M-00003-of-00003.gguf",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsharded_files() {
+        let models = vec![
+            "dolphin-2.9-llama3-8b.Q4_0.gguf",
+            "model-1-of-2.gguf",
+            "model-00001-of-00002.bin",
+        ];
+
+        for model in models {
+            assert_eq!(shard_files(model), vec![model]);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use {
+    anyhow::{Context, Result},
+    chrono::{DateTime, Utc},
+    log::error,
+    serde::{Deserialize, Serialize},
+    serde_json::{Map, Value as JsonValue},
+};
+
+use crate::config::When;
+
+/// A job added with `schedule_task`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueuedJob {
+    pub task_name: String,
+    #[serde(flatten)]
+    pub when: When,
+    pub params: Map<String, JsonValue>,
+    pub last_run: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunState {
+    /// Waiting for the task's previous run to finish or for a retry.
+    Waiting,
+    Running,
+}
+
+/// A run of a task that has started but not finished.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueuedRun {
+    pub task_name: String,
+    pub params: Map<String, JsonValue>,
+    pub state: RunState,
+    /// How many attempts have been started, including one that was running when the service
+    /// stopped.
+    pub attempts: u32,
+    pub started: DateTime<Utc>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Queue {
+    #[serde(default)]
+    jobs: BTreeMap<String, QueuedJob>,
+    #[serde(default)]
+    runs: BTreeMap<String, QueuedRun>,
+}
+
+/// Jobs and unfinished runs, kept in one JSON file that's rewritten on every change so nothing is
+/// lost when the service stops.
+pub struct JobQueue {
+    path: PathBuf,
+    queue: Queue,
+}
+
+impl JobQueue {
+    pub fn load(path: &Path) -> Result<Self> {
+        let queue = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("invalid job queue in {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Queue::default(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("unable to read job queue {}", path.display()))
+            }
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            queue,
+        })
+    }
+
+    pub fn jobs(&self) -> impl Iterator<Item = (&String, &QueuedJob)> {
+        self.queue.jobs.iter()
+    }
+
+    pub fn runs(&self) -> impl Iterator<Item = (&String, &QueuedRun)> {
+        self.queue.runs.iter()
+    }
+
+    pub fn add_job(&mut self, id: &str, job: QueuedJob) {
+        self.queue.jobs.insert(id.to_string(), job);
+        self.save();
+    }
+
+    pub fn set_last_run(&mut self, id: &str, last_run: DateTime<Utc>) {
+        if let Some(job) = self.queue.jobs.get_mut(id) {
+            job.last_run = last_run;
+            self.save();
+        }
+    }
+
+    pub fn remove_job(&mut self, id: &str) {
+        if self.queue.jobs.remove(id).is_some() {
+            self.save();
+        }
+    }
+
+    pub fn add_run(&mut self, id: &str, run: QueuedRun) {
+        self.queue.runs.insert(id.to_string(), run);
+        self.save();
+    }
+
+    pub fn set_run_state(&mut self, id: &str, state: RunState, attempts: u32) {
+        if let Some(run) = self.queue.runs.get_mut(id) {
+            run.state = state;
+            run.attempts = attempts;
+            self.save();
+        }
+    }
+
+    pub fn remove_run(&mut self, id: &str) {
+        if self.queue.runs.remove(id).is_some() {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = self.write() {
+            error!("Unable to save job queue: {:#}", e);
+        }
+    }
+
+    fn write(&self) -> Result<()> {
+        // Write then rename so a crash never leaves a half written file behind.
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_string_pretty(&self.queue)?)
+            .with_context(|| format!("unable to write {}", temp.display()))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("unable to write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_job_queue() {
+        let path = std::env::temp_dir().join(format!("salient-jobs-{}", std::process::id()));
+        let time = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+
+        let mut queue = JobQueue::load(&path).unwrap();
+        queue.add_job(
+            "Remind-1",
+            QueuedJob {
+                task_name: String::from("Remind"),
+                when: When::At(String::from("2026-11-01T09:00")),
+                params: json!({ "text": "Stretch" }).as_object().unwrap().clone(),
+                last_run: time("2026-10-19T12:00:00Z"),
+            },
+        );
+        queue.add_job(
+            "Remind-2",
+            QueuedJob {
+                task_name: String::from("Remind"),
+                when: When::Cron(String::from("0 0 * * * * *")),
+                params: Map::new(),
+                last_run: time("2026-10-19T12:00:00Z"),
+            },
+        );
+        queue.set_last_run("Remind-2", time("2026-10-19T13:00:00Z"));
+        queue.remove_job("Remind-1");
+        queue.add_run(
+            "Weather-1",
+            QueuedRun {
+                task_name: String::from("Weather"),
+                params: Map::new(),
+                state: RunState::Waiting,
+                attempts: 0,
+                started: time("2026-10-19T13:00:00Z"),
+            },
+        );
+        queue.set_run_state("Weather-1", RunState::Running, 1);
+
+        let queue = JobQueue::load(&path).unwrap();
+        let jobs: Vec<_> = queue.jobs().collect();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].0, "Remind-2");
+        assert_eq!(jobs[0].1.last_run, time("2026-10-19T13:00:00Z"));
+        let runs: Vec<_> = queue.runs().collect();
+        assert_eq!(runs[0].1.state, RunState::Running);
+        assert_eq!(runs[0].1.attempts, 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,40 +1,412 @@
 mod ai_worker;
+mod cache;
+mod cassette;
+mod check;
 mod config;
+mod gguf;
+mod hub;
+mod job_queue;
+mod models;
+mod secrets;
 // mod data_broker;
 mod task_execution;
+#[cfg(feature = "transcribe")]
+mod transcriber;
+mod watcher;
 
 use std::{
+    collections::HashSet,
     error::Error,
     fs,
+    path::PathBuf,
     sync::{Arc, Mutex as SyncMutex},
+    time::Duration,
 };
 
 use {
-    log::{debug, error, info},
+    clap::{Parser, Subcommand},
+    log::{debug, error, info, warn},
     percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC},
-    tokio::{sync::Mutex, time::sleep},
+    serde_json::json,
+    tokio::{
+        signal::unix::{signal, SignalKind},
+        sync::Mutex,
+        task::JoinHandle,
+        time::sleep,
+    },
 };
 
 use {
-    ai_worker::{AIWorker, Message},
-    config::Config,
-    task_execution::{Scheduler, TaskManager},
+    ai_worker::{AIWorker, EvalOptions, Message},
+    cassette::{Cassette, Invocation},
+    config::{Config, Model},
+    secrets::SecretStore,
+    task_execution::{
+        log_run, ActiveScript, JobSpec, Scheduler, SchedulerHandle, Task, TaskManager,
+    },
+    watcher::Watcher,
 };
 
+#[cfg(feature = "transcribe")]
+use {std::path::Path, transcriber::Transcriber};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Config file to use instead of searching the default locations
+    #[arg(long, global = true, value_name = "FILE", env = "SALIENT_CONFIG")]
+    config: Option<PathBuf>,
+    /// Record every registered function call, including `llm_eval`, to a cassette file
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Run the tasks from a recorded cassette with function calls served from it, then exit
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the config and scripts and preview task schedules
+    Check,
+    /// Manage models in the Hugging Face cache
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ModelsCommand {
+    /// List cached GGUF models
+    List,
+    /// Download the models referenced by the config
+    Pull,
+    /// Remove a cached repo (`org/name`) or model file (`org/name/file.gguf`)
+    Rm { target: String },
+    /// Show the chat template and special tokens of a model, defaulting to the configured one
+    Info { target: Option<String> },
+}
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Re-reads the config and swaps the worker over to its model if it changed. Runs on a blocking
+/// thread since acquiring the worker waits for any in-flight evaluation to drain.
+async fn reload_model(worker: Arc<SyncMutex<AIWorker>>, config_path: PathBuf) {
+    let result = tokio::task::spawn_blocking(move || {
+        let config = Config::new(Some(&config_path))?;
+        let mut worker = worker.lock().unwrap();
+        worker.set_hub(config.hub);
+        worker.set_cache(config.cache.as_ref())?;
+        worker.load_model(&config.model)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => info!("Config reloaded"),
+        Ok(Err(e)) => error!("Failed to reload model from config: {}", e),
+        Err(e) => error!("Model reload task failed: {}", e),
+    }
+}
+
+/// Watches the config, its fragments, every script and the modules they `require`.
+async fn watch_scripts(
+    watcher: &mut Watcher,
+    config: &Config,
+    task_manager: &Mutex<TaskManager>,
+) -> anyhow::Result<()> {
+    let fragment_dir = config::fragment_dir(&config.path);
+
+    let mut files = vec![config.path.clone(), fragment_dir.clone()];
+    files.extend(config.scripts.iter().map(|script| script.path.clone()));
+    files.extend(
+        task_manager
+            .lock()
+            .await
+            .modules()
+            .await?
+            .into_iter()
+            .map(|(_, path)| path),
+    );
+
+    watcher.watch(files, vec![fragment_dir])
+}
+
+/// Applies changed files to the scripts and tasks without touching the model. A script is run
+/// again, along with its tasks' `setup`, when it or any `require`d module changed, or when the
+/// config changed its tasks. Task schedules are then updated to match the config.
+async fn reload_scripts(
+    config: &mut Config,
+    changed: &HashSet<PathBuf>,
+    task_manager: &Mutex<TaskManager>,
+    scheduler: &mut Scheduler,
+) -> anyhow::Result<()> {
+    let mut task_manager = task_manager.lock().await;
+
+    let mut known: HashSet<PathBuf> = config
+        .scripts
+        .iter()
+        .map(|script| watcher::normalize(&script.path))
+        .collect();
+
+    let mut modules_changed = false;
+    for (name, path) in task_manager.modules().await? {
+        let path = watcher::normalize(&path);
+        if changed.contains(&path) {
+            info!("Module {} changed", name);
+            task_manager.unload_module(&name).await?;
+            modules_changed = true;
+        }
+        known.insert(path);
+    }
+
+    // Anything that isn't a script or module is the config or one of its fragments.
+    let new_config = if changed.iter().any(|path| !known.contains(path)) {
+        info!("Config changed, reloading scripts and tasks");
+        Some(Config::new(Some(&config.path))?)
+    } else {
+        None
+    };
+    let scripts = new_config
+        .as_ref()
+        .map_or(&config.scripts, |new_config| &new_config.scripts);
+    let scheduling = new_config
+        .as_ref()
+        .map_or(&config.scheduling, |new_config| &new_config.scheduling);
+
+    let task_names = |script: &config::Script| {
+        script
+            .tasks
+            .iter()
+            .map(|task| (task.name.clone(), task.handler().to_string()))
+            .collect::<Vec<(String, String)>>()
+    };
+
+    for script in scripts {
+        let tasks_changed = config
+            .scripts
+            .iter()
+            .find(|old| old.path == script.path)
+            .is_none_or(|old| task_names(old) != task_names(script));
+
+        if !modules_changed
+            && !tasks_changed
+            && !changed.contains(&watcher::normalize(&script.path))
+        {
+            continue;
+        }
+
+        info!("Reloading script {}", script.path.display());
+        match fs::read_to_string(&script.path) {
+            Ok(contents) => {
+                if let Err(e) = task_manager.register_script(&contents, script).await {
+                    error!("Failed to reload {}: {}", script.path.display(), e);
+                }
+            }
+            Err(e) => error!("Unable to read {}: {}", script.path.display(), e),
+        }
+    }
+
+    // Tasks whose script never loaded can't run, so leave them unscheduled.
+    let tasks: Vec<config::Task> = scripts
+        .iter()
+        .flat_map(|script| &script.tasks)
+        .filter(|task| task_manager.has_task(&task.name))
+        .cloned()
+        .collect();
+    scheduler.sync_tasks(&tasks, scheduling)?;
+
+    for task in config.scripts.iter().flat_map(|script| &script.tasks) {
+        if !tasks.iter().any(|synced| synced.name == task.name) {
+            task_manager.unregister_task(&task.name).await;
+        }
+    }
+
+    if let Some(new_config) = new_config {
+        if let Some(store) = task_manager.scope.lock().unwrap().get_mut::<SecretStore>() {
+            store.set_scopes(&new_config.scripts);
+        }
+        config.scripts = new_config.scripts;
+        config.scheduling = new_config.scheduling;
+    }
+
+    Ok(())
+}
+
+/// Runs the task runs recorded in a cassette one after another, in the order they started.
+async fn replay(config: &Config, task_manager: &Mutex<TaskManager>, runs: Vec<Invocation>) {
+    info!("Replaying {} task run(s)", runs.len());
+
+    for invocation in runs {
+        let Some(task) = config
+            .scripts
+            .iter()
+            .flat_map(|script| &script.tasks)
+            .find(|task| task.name == invocation.task)
+        else {
+            error!(
+                "Skipping run of task {}, it no longer exists",
+                invocation.task
+            );
+            continue;
+        };
+
+        info!(
+            "Replaying task {} started at {}",
+            task.name, invocation.started
+        );
+        let scheduled = {
+            let mut task_manager = task_manager.lock().await;
+            if task_manager.handler(&task.name) != Some(invocation.handler.as_str()) {
+                warn!(
+                    "Task {} was recorded running handler {}",
+                    task.name, invocation.handler
+                );
+            }
+            task_manager
+                .schedule(Task {
+                    task_name: task.name.clone(),
+                    params: invocation.params,
+                    timeout: task.policy.timeout.map(Duration::from_secs),
+                    abort: Default::default(),
+                })
+                .await
+        };
+
+        match scheduled {
+            Ok(handle) => match handle.await {
+                Ok(run) => log_run(&run),
+                Err(e) => error!("Task {} panicked: {}", task.name, e),
+            },
+            Err(e) => error!("Unable to replay task {}: {}", task.name, e),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+    secrets::init_logger();
+
+    let cli = Cli::parse();
+    // Check lists problems in the config along with everything else, other commands stop at them.
+    let (config, problems) = Config::load(cli.config.as_deref())?;
+    if !problems.is_empty() && !matches!(cli.command, Some(Command::Check)) {
+        return Err(problems.join("\n").into());
+    }
+
+    match cli.command {
+        None => {
+            let cassette = match (&cli.record, &cli.replay) {
+                (Some(path), _) => Some(Cassette::record(path)?),
+                (_, Some(path)) => Some(Cassette::replay(path)?),
+                _ => None,
+            };
+            run(config, cassette).await
+        }
+        Some(Command::Check) => {
+            check::check(&config, problems)?;
+            Ok(())
+        }
+        Some(Command::Models { command }) => {
+            match command {
+                ModelsCommand::List => models::list(&config.hub),
+                ModelsCommand::Pull => models::pull(&config),
+                ModelsCommand::Rm { target } => models::remove(&config.hub, &target),
+                ModelsCommand::Info { target } => models::info(&config, target.as_deref()),
+            }?;
+            Ok(())
+        }
+    }
+}
+
+async fn run(mut config: Config, cassette: Option<Cassette>) -> Result<(), Box<dyn Error>> {
     info!("Starting service");
 
-    let config = Config::new()?;
+    // Replayed runs never call the model, so don't spend time loading it.
+    if matches!(cassette, Some(Cassette::Replay { .. })) {
+        config.model.lazy = true;
+    }
+
+    let mut worker = AIWorker::new(&config.model, &config.hub)?;
+    worker.set_cache(config.cache.as_ref())?;
+    let worker = Arc::new(SyncMutex::new(worker));
+    let mut task_manager = TaskManager::new().await?;
+    let mut replay_runs = None;
+    if let Some(mut cassette) = cassette {
+        replay_runs = cassette.take_runs();
+        task_manager.set_cassette(cassette);
+    }
+    let task_manager = Arc::new(Mutex::new(task_manager));
+
+    {
+        let worker = worker.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(IDLE_CHECK_INTERVAL).await;
+                // A held lock means an evaluation is running, so the model isn't idle.
+                if let Ok(mut worker) = worker.try_lock() {
+                    worker.unload_if_idle();
+                }
+            }
+        });
+    }
 
-    let worker = Arc::new(SyncMutex::new(AIWorker::new(&config.model)?));
-    let task_manager = Arc::new(Mutex::new(TaskManager::new().await?));
+    let mut scheduler = Scheduler::new(&config.scheduling)?;
 
     {
         let task_manager = task_manager.lock().await;
         let mut scope = task_manager.scope.lock().unwrap();
-        scope.insert::<Arc<SyncMutex<AIWorker>>>(worker);
+        scope.insert::<Arc<SyncMutex<AIWorker>>>(worker.clone());
+        scope.insert(scheduler.handle());
+
+        let mut secrets = match &config.secrets {
+            Some(secrets) => SecretStore::load(secrets)?,
+            None => SecretStore::default(),
+        };
+        secrets.set_scopes(&config.scripts);
+        scope.insert(secrets);
+    }
+
+    if let Some(transcription) = &config.transcription {
+        #[cfg(feature = "transcribe")]
+        {
+            let transcriber = Arc::new(Transcriber::new(transcription, &config.hub)?);
+            let mut task_manager = task_manager.lock().await;
+            task_manager
+                .scope
+                .lock()
+                .unwrap()
+                .insert::<Arc<Transcriber>>(transcriber);
+
+            task_manager
+                .register_function("transcribe", |scope, params| {
+                    let transcriber = scope.get_mut::<Arc<Transcriber>>().unwrap().clone();
+
+                    let Some(path) = params.get("path").and_then(|path| path.as_str()) else {
+                        return json!({ "error": "path parameter not found" });
+                    };
+                    let language = params
+                        .get("language")
+                        .and_then(|language| language.as_str());
+
+                    match transcriber.transcribe(Path::new(path), language) {
+                        Ok(transcript) => serde_json::to_value(transcript).unwrap(),
+                        Err(e) => {
+                            error!("Error in transcribe: {}", e);
+                            json!({ "error": e.to_string() })
+                        }
+                    }
+                })
+                .await
+                .unwrap();
+        }
+
+        #[cfg(not(feature = "transcribe"))]
+        warn!(
+            "Ignoring transcription model {:?}, salient was built without the transcribe feature",
+            transcription.source
+        );
     }
 
     {
@@ -48,12 +420,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     .lock()
                     .unwrap();
 
+                let options = match serde_json::from_value::<EvalOptions>(params.clone()) {
+                    Ok(options) => options,
+                    Err(e) => return json!({ "error": format!("Invalid options: {}", e) }),
+                };
+
                 if let Some(messages) = params.get("messages") {
                     if let Ok(messages) = serde_json::from_value::<Vec<Message>>(messages.clone()) {
-                        let messages = messages.to_owned();
-                        let result = llm.eval(&messages).unwrap();
-
-                        serde_json::to_value(result).unwrap()
+                        match llm.eval(&messages, &options) {
+                            Ok(result) => serde_json::to_value(result).unwrap(),
+                            Err(e) => {
+                                error!("Error in llm_eval: {}", e);
+                                json!({ "error": e.to_string() })
+                            }
+                        }
                     } else {
                         serde_json::from_str("Messages were not in correct format.").unwrap()
                     }
@@ -64,6 +444,98 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .await
             .unwrap();
 
+        task_manager
+            .register_function("model_load", |scope, params| {
+                let mut llm = scope
+                    .get_mut::<Arc<SyncMutex<AIWorker>>>()
+                    .unwrap()
+                    .lock()
+                    .unwrap();
+
+                match serde_json::from_value::<Model>(params) {
+                    Ok(model) => match llm.load_model(&model) {
+                        Ok(()) => json!({ "loaded": true }),
+                        Err(e) => {
+                            error!("Error in model_load: {}", e);
+                            json!({ "loaded": false, "error": e.to_string() })
+                        }
+                    },
+                    Err(e) => json!({ "loaded": false, "error": format!("Invalid model: {}", e) }),
+                }
+            })
+            .await
+            .unwrap();
+
+        task_manager
+            .register_function("cache_stats", |scope, _params| {
+                let llm = scope
+                    .get_mut::<Arc<SyncMutex<AIWorker>>>()
+                    .unwrap()
+                    .lock()
+                    .unwrap();
+
+                match llm.cache_stats() {
+                    Ok(Some(stats)) => serde_json::to_value(stats).unwrap(),
+                    Ok(None) => json!({ "error": "response cache is not configured" }),
+                    Err(e) => json!({ "error": e.to_string() }),
+                }
+            })
+            .await
+            .unwrap();
+
+        task_manager
+            .register_live_function("secret", |scope, params| {
+                let Some(name) = params.as_str() else {
+                    error!("secret expects the name of a secret");
+                    return json!({ "error": "secret expects the name of a secret" });
+                };
+                let script = scope
+                    .get_mut::<ActiveScript>()
+                    .and_then(|active| active.0.clone());
+                let store = scope.get_mut::<SecretStore>().unwrap();
+
+                match store.get(script.as_deref(), name) {
+                    Ok(value) => json!(value),
+                    Err(e) => {
+                        error!("Error in secret: {}", e);
+                        json!({ "error": e.to_string() })
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+        task_manager
+            .register_live_function("schedule_task", |scope, params| {
+                let handle = scope.get_mut::<SchedulerHandle>().unwrap();
+
+                match serde_json::from_value::<JobSpec>(params) {
+                    Ok(spec) => match handle.schedule(spec) {
+                        Ok(id) => json!(id),
+                        Err(e) => {
+                            error!("Error in schedule_task: {:#}", e);
+                            json!({ "error": format!("{:#}", e) })
+                        }
+                    },
+                    Err(e) => json!({ "error": format!("Invalid job: {}", e) }),
+                }
+            })
+            .await
+            .unwrap();
+
+        task_manager
+            .register_live_function("cancel_task", |scope, params| {
+                let Some(id) = params.as_str() else {
+                    error!("cancel_task expects the id of a job");
+                    return json!({ "error": "cancel_task expects the id of a job" });
+                };
+                let handle = scope.get_mut::<SchedulerHandle>().unwrap();
+
+                json!(handle.cancel(id))
+            })
+            .await
+            .unwrap();
+
         task_manager
             .register_function("http_get", |_scope, params| {
                 debug!("Running http_get");
@@ -129,8 +601,6 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .unwrap();
     }
 
-    let mut scheduler = Scheduler::new().unwrap();
-
     for script in config.scripts.iter() {
         let script_contents = fs::read_to_string(script.path.to_str().unwrap()).unwrap();
         task_manager
@@ -140,14 +610,77 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .await
             .unwrap();
         for task in script.tasks.iter() {
-            scheduler
-                .register_task(task.name.clone(), task.cron.clone())
-                .unwrap();
+            scheduler.register_task(task).unwrap();
         }
     }
 
+    if let Some(runs) = replay_runs {
+        replay(&config, &task_manager, runs).await;
+        task_manager.lock().await.shutdown().await;
+        return Ok(());
+    }
+
+    scheduler.resume(task_manager.clone());
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    let mut watcher = match Watcher::new() {
+        Ok(mut watcher) => match watch_scripts(&mut watcher, &config, &task_manager).await {
+            Ok(()) => Some(watcher),
+            Err(e) => {
+                error!("Unable to watch scripts, hot reload is disabled: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            error!("Unable to watch scripts, hot reload is disabled: {}", e);
+            None
+        }
+    };
+
+    // Reloads run in the background so a slow model load doesn't hold up scheduled tasks.
+    let mut reload: Option<JoinHandle<()>> = None;
+
     loop {
-        scheduler.run(task_manager.clone()).unwrap();
-        sleep(std::time::Duration::from_millis(500)).await;
+        tokio::select! {
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+            _ = hangup.recv() => {
+                if reload.as_ref().is_some_and(|reload| !reload.is_finished()) {
+                    warn!("Received SIGHUP while a reload is in progress, ignoring");
+                } else {
+                    info!("Received SIGHUP, reloading config");
+                    reload = Some(tokio::spawn(reload_model(worker.clone(), config.path.clone())));
+                }
+            }
+            changed = async {
+                match watcher.as_mut() {
+                    Some(watcher) => watcher.changed().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                if let Err(e) =
+                    reload_scripts(&mut config, &changed, &task_manager, &mut scheduler).await
+                {
+                    error!("Failed to reload scripts: {}", e);
+                }
+                if let Some(watcher) = watcher.as_mut() {
+                    if let Err(e) = watch_scripts(watcher, &config, &task_manager).await {
+                        error!("Unable to update watched files: {}", e);
+                    }
+                }
+            }
+            _ = scheduler.wait() => {
+                scheduler.fire(task_manager.clone());
+            }
+        }
     }
+
+    info!("Shutting down");
+    scheduler.stop().await;
+    task_manager.lock().await.shutdown().await;
+
+    Ok(())
 }
@@ -0,0 +1,244 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    config::{Config, Hub, ModelSource},
+    gguf::{Metadata, SPECIAL_TOKEN_KEYS},
+    hub,
+};
+
+const REPO_PREFIX: &str = "models--";
+
+/// A GGUF file in the hub cache.
+struct CachedModel {
+    repo: String,
+    file: String,
+    revision: String,
+    path: PathBuf,
+}
+
+/// Prints every cached GGUF along with what can be read from its metadata.
+pub fn list(hub: &Hub) -> Result<()> {
+    let models = cached_models(hub)?;
+
+    if models.is_empty() {
+        println!("No models cached in {}", hub.cache().path().display());
+        return Ok(());
+    }
+
+    println!(
+        "{:<48} {:<56} {:<8} {:>10} {:<8} {:>8}",
+        "REPO", "FILE", "REVISION", "SIZE", "QUANT", "CONTEXT"
+    );
+
+    for model in models {
+        let size = fs::metadata(&model.path).map(|m| m.len()).unwrap_or(0);
+        let (quant, context) = match Metadata::read(&model.path) {
+            Ok(metadata) => (
+                metadata.quantization().unwrap_or("?").to_string(),
+                metadata
+                    .context_length()
+                    .map_or(String::from("?"), |n| n.to_string()),
+            ),
+            Err(_) => (String::from("?"), String::from("?")),
+        };
+
+        println!(
+            "{:<48} {:<56} {:<8} {:>10} {:<8} {:>8}",
+            model.repo,
+            model.file,
+            &model.revision[..model.revision.len().min(8)],
+            format_size(size),
+            quant,
+            context
+        );
+    }
+
+    Ok(())
+}
+
+/// Downloads every model referenced by the config so later starts don't need the network.
+pub fn pull(config: &Config) -> Result<()> {
+    for model in configured_models(config) {
+        let path = model.get_or_load(&config.hub)?;
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Removes `org/name` entirely or a single `org/name/file.gguf` (all shards for split GGUFs).
+pub fn remove(hub: &Hub, target: &str) -> Result<()> {
+    let root = hub.cache().path().clone();
+    let repo_dir = |repo: &str| root.join(format!("{}{}", REPO_PREFIX, repo.replace('/', "--")));
+
+    let whole_repo = repo_dir(target);
+    if whole_repo.is_dir() {
+        fs::remove_dir_all(&whole_repo)
+            .with_context(|| format!("unable to remove {}", whole_repo.display()))?;
+        println!("Removed {}", target);
+        return Ok(());
+    }
+
+    let Some((repo, file)) = target.rsplit_once('/') else {
+        bail!("{} is not cached", target);
+    };
+
+    let mut removed = false;
+    for model in cached_models(hub)? {
+        if model.repo == repo && hub::shard_files(file).contains(&model.file) {
+            let blob = fs::canonicalize(&model.path)?;
+            fs::remove_file(&model.path)?;
+            if blob != model.path {
+                fs::remove_file(&blob)?;
+            }
+            println!("Removed {}/{}", model.repo, model.file);
+            removed = true;
+        }
+    }
+
+    if !removed {
+        bail!("{} is not cached", target);
+    }
+
+    Ok(())
+}
+
+/// Dumps the chat template and special tokens of a GGUF. `target` may be a path on disk or
+/// `org/name/file.gguf` from the cache, and defaults to the configured model.
+pub fn info(config: &Config, target: Option<&str>) -> Result<()> {
+    // Never download here; info should only look at what's already on disk.
+    let hub = Hub {
+        offline: true,
+        ..config.hub.clone()
+    };
+
+    let path = match target {
+        Some(target) if Path::new(target).exists() => PathBuf::from(target),
+        Some(target) => {
+            let (repo, file) = target
+                .rsplit_once('/')
+                .with_context(|| format!("{} is not a file or cached model", target))?;
+            hub::fetch(&hub, repo, file, None)?
+        }
+        None => config.model.get_or_load(&hub)?,
+    };
+
+    let metadata = Metadata::read(&path)?;
+
+    println!("Path: {}", path.display());
+    println!("GGUF version: {}", metadata.version);
+    println!("Tensors: {}", metadata.tensor_count);
+    println!(
+        "Architecture: {}",
+        metadata.architecture().unwrap_or("unknown")
+    );
+    println!(
+        "Quantization: {}",
+        metadata.quantization().unwrap_or("unknown")
+    );
+    if let Some(context_length) = metadata.context_length() {
+        println!("Context length: {}", context_length);
+    }
+
+    println!("\nSpecial tokens:");
+    for (name, key) in SPECIAL_TOKEN_KEYS {
+        if let Some(id) = metadata.get(key).and_then(|id| id.as_u64()) {
+            println!(
+                "  {:<10} {:>8} {:?}",
+                name,
+                id,
+                metadata.token(id).unwrap_or("")
+            );
+        }
+    }
+
+    println!("\nChat template:");
+    println!("{}", metadata.chat_template().unwrap_or("(none)"));
+
+    Ok(())
+}
+
+fn configured_models(config: &Config) -> Vec<&ModelSource> {
+    let mut models = vec![&config.model.source];
+    if let Some(draft) = &config.model.draft {
+        models.push(&draft.source);
+    }
+    if let Some(mmproj) = &config.model.mmproj {
+        models.push(mmproj);
+    }
+    if let Some(transcription) = &config.transcription {
+        models.push(&transcription.source);
+    }
+    models
+}
+
+fn cached_models(hub: &Hub) -> Result<Vec<CachedModel>> {
+    let root = hub.cache().path().clone();
+    let mut models = vec![];
+
+    if !root.is_dir() {
+        return Ok(models);
+    }
+
+    for repo_entry in fs::read_dir(&root)? {
+        let repo_path = repo_entry?.path();
+        let Some(repo) = repo_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(REPO_PREFIX))
+        else {
+            continue;
+        };
+        let repo = repo.replacen("--", "/", 1);
+
+        let snapshots = repo_path.join("snapshots");
+        if !snapshots.is_dir() {
+            continue;
+        }
+
+        for snapshot in fs::read_dir(&snapshots)? {
+            let snapshot = snapshot?.path();
+            let revision = snapshot
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_string();
+
+            for file in fs::read_dir(&snapshot)? {
+                let path = file?.path();
+                if path.extension().is_some_and(|ext| ext == "gguf") {
+                    models.push(CachedModel {
+                        repo: repo.clone(),
+                        file: path
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .unwrap_or_default()
+                            .to_string(),
+                        revision: revision.clone(),
+                        path,
+                    });
+                }
+            }
+        }
+    }
+
+    models.sort_by(|a, b| (&a.repo, &a.file).cmp(&(&b.repo, &b.file)));
+
+    Ok(models)
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
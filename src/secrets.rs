@@ -0,0 +1,305 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use {
+    anyhow::{anyhow, bail, Context, Result},
+    log::warn,
+};
+
+use crate::config::{Script, Secrets};
+
+const REDACTED: &str = "[REDACTED]";
+/// Shorter values, such as `1` or `true`, are too likely to turn up in unrelated text to redact.
+const MIN_REDACTED_LEN: usize = 6;
+
+/// Every secret value loaded, so log output can be scrubbed of them.
+static REDACTIONS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Secret values along with which of them each script declared it may read.
+#[derive(Default)]
+pub struct SecretStore {
+    values: HashMap<String, String>,
+    scopes: HashMap<PathBuf, HashSet<String>>,
+}
+
+impl SecretStore {
+    pub fn load(config: &Secrets) -> Result<Self> {
+        let values = match config {
+            Secrets::EnvFile { path } => {
+                require_private(path)?;
+                read_env(File::open(path).with_context(|| open_error(path))?)?
+            }
+            Secrets::File { path } => {
+                require_private(path)?;
+                toml::from_str(&fs::read_to_string(path).with_context(|| open_error(path))?)
+                    .with_context(|| format!("invalid secrets file {}", path.display()))?
+            }
+            Secrets::Encrypted { path, identity } => {
+                require_private(identity)?;
+                decrypt(path, identity)?
+            }
+        };
+
+        let mut redactions = REDACTIONS.write().unwrap();
+        for (name, value) in &values {
+            if value.len() >= MIN_REDACTED_LEN {
+                redactions.push(value.clone());
+            } else if !value.is_empty() {
+                warn!(
+                    "Secret {} is shorter than {} characters and won't be redacted from output",
+                    name, MIN_REDACTED_LEN
+                );
+            }
+        }
+        // Longest first so a secret containing another is replaced whole.
+        redactions.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        redactions.dedup();
+
+        Ok(Self {
+            values,
+            scopes: HashMap::new(),
+        })
+    }
+
+    /// Records which secrets each script declared.
+    pub fn set_scopes(&mut self, scripts: &[Script]) {
+        self.scopes = scripts
+            .iter()
+            .map(|script| {
+                (
+                    script.path.clone(),
+                    script.secrets.iter().cloned().collect(),
+                )
+            })
+            .collect();
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    /// Reads `name` on behalf of `script`, which must have declared it.
+    pub fn get(&self, script: Option<&Path>, name: &str) -> Result<&str> {
+        let Some(script) = script else {
+            bail!("secrets can only be read while a script is running");
+        };
+
+        if !self
+            .scopes
+            .get(script)
+            .is_some_and(|declared| declared.contains(name))
+        {
+            bail!(
+                "secret {} is not declared by script {}",
+                name,
+                script.display()
+            );
+        }
+
+        self.values
+            .get(name)
+            .map(String::as_str)
+            .with_context(|| format!("secret {} is not set", name))
+    }
+}
+
+/// Replaces every loaded secret value in `text`.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let redactions = REDACTIONS.read().unwrap();
+    let mut text = Cow::Borrowed(text);
+
+    for value in redactions.iter() {
+        if text.contains(value.as_str()) {
+            text = Cow::Owned(text.replace(value.as_str(), REDACTED));
+        }
+    }
+
+    text
+}
+
+/// Sets up `env_logger` with secrets redacted from every message.
+pub fn init_logger() {
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            let level_style = buf.default_level_style(record.level());
+            writeln!(
+                buf,
+                "[{} {level_style}{:<5}{level_style:#} {}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                redact(&record.args().to_string())
+            )
+        })
+        .init();
+}
+
+fn read_env(reader: impl Read) -> Result<HashMap<String, String>> {
+    dotenvy::from_read_iter(reader)
+        .map(|entry| entry.with_context(|| "invalid env file"))
+        .collect()
+}
+
+/// Decrypts an age file encrypted to one of the X25519 identities in `identity`.
+fn decrypt(path: &Path, identity: &Path) -> Result<HashMap<String, String>> {
+    let identities = fs::read_to_string(identity)
+        .with_context(|| open_error(identity))?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            age::x25519::Identity::from_str(line)
+                .map_err(|e| anyhow!("invalid identity in {}: {}", identity.display(), e))
+        })
+        .collect::<Result<Vec<age::x25519::Identity>>>()?;
+
+    let file = File::open(path).with_context(|| open_error(path))?;
+    let decryptor = age::Decryptor::new(file)
+        .with_context(|| format!("{} is not an age file", path.display()))?;
+    if decryptor.is_scrypt() {
+        bail!(
+            "{} is passphrase encrypted, encrypt it to an identity instead",
+            path.display()
+        );
+    }
+
+    let reader = decryptor
+        .decrypt(
+            identities
+                .iter()
+                .map(|identity| identity as &dyn age::Identity),
+        )
+        .with_context(|| format!("unable to decrypt {}", path.display()))?;
+
+    read_env(reader)
+}
+
+fn is_shared(path: &Path) -> Result<bool> {
+    let mode = fs::metadata(path)
+        .with_context(|| open_error(path))?
+        .permissions()
+        .mode();
+    Ok(mode & 0o077 != 0)
+}
+
+fn require_private(path: &Path) -> Result<()> {
+    if is_shared(path)? {
+        bail!(
+            "{} must only be accessible by its owner (mode 0600)",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn open_error(path: &Path) -> String {
+    format!("unable to read {}", path.display())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use age::secrecy::ExposeSecret;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("salient-{}-{}", name, std::process::id()))
+    }
+
+    fn write_private(path: &Path, contents: &[u8]) {
+        fs::write(path, contents).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+
+    #[test]
+    fn test_scoped_secrets() {
+        let path = temp_path("secrets.toml");
+        write_private(
+            &path,
+            b"WEATHER_KEY = \"weather-abc123\"\nOTHER = \"other\"\n",
+        );
+
+        let mut store = SecretStore::load(&Secrets::File { path: path.clone() }).unwrap();
+        store.set_scopes(&[Script {
+            path: PathBuf::from("./scripts/weather.lua"),
+            secrets: vec![String::from("WEATHER_KEY")],
+            tasks: vec![],
+        }]);
+
+        let script = Path::new("./scripts/weather.lua");
+        assert_eq!(
+            store.get(Some(script), "WEATHER_KEY").unwrap(),
+            "weather-abc123"
+        );
+        assert!(store.get(Some(script), "OTHER").is_err());
+        assert!(store
+            .get(Some(Path::new("./scripts/other.lua")), "WEATHER_KEY")
+            .is_err());
+        assert!(store.get(None, "WEATHER_KEY").is_err());
+
+        assert_eq!(
+            redact("GET https://example.com/?appid=weather-abc123"),
+            "GET https://example.com/?appid=[REDACTED]"
+        );
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(SecretStore::load(&Secrets::File { path: path.clone() }).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_env_file_secrets() {
+        let path = temp_path("secrets.env");
+        write_private(&path, b"MAIL_TOKEN=mail-xyz789\nREGION=us\n");
+
+        let store = SecretStore::load(&Secrets::EnvFile { path: path.clone() }).unwrap();
+        assert_eq!(store.values["MAIL_TOKEN"], "mail-xyz789");
+
+        // Short values would mangle any text that happens to contain them.
+        assert_eq!(
+            redact("token mail-xyz789 for us-east"),
+            "token [REDACTED] for us-east"
+        );
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(SecretStore::load(&Secrets::EnvFile { path: path.clone() }).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_secrets() {
+        let key = age::x25519::Identity::generate();
+        let identity = temp_path("identity.txt");
+        write_private(&identity, key.to_string().expose_secret().as_bytes());
+
+        let path = temp_path("secrets.env.age");
+        let recipient = key.to_public();
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                .unwrap();
+        let mut writer = encryptor.wrap_output(File::create(&path).unwrap()).unwrap();
+        writer.write_all(b"API_KEY=\"encrypted-value\"\n").unwrap();
+        writer.finish().unwrap();
+
+        let store = SecretStore::load(&Secrets::Encrypted {
+            path: path.clone(),
+            identity: identity.clone(),
+        })
+        .unwrap();
+        assert!(store.contains("API_KEY"));
+        assert_eq!(store.values["API_KEY"], "encrypted-value");
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&identity).unwrap();
+    }
+}
@@ -1,26 +1,56 @@
 use std::{
     any::{Any, TypeId},
-    collections::HashMap,
-    env,
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
     error::Error,
+    fs,
+    future::Future,
+    io, iter,
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::{Arc, Mutex as StdMutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant},
 };
 
 use {
-    anyhow::Result,
-    chrono::Utc,
+    anyhow::{bail, Context, Result},
+    chrono::{DateTime, NaiveDateTime, TimeDelta, TimeZone, Utc},
+    chrono_tz::Tz,
     cron::Schedule,
-    log::{debug, error},
+    log::{debug, error, info, warn},
     mlua::{prelude::*, LuaSerdeExt},
-    serde_json::Value as JsonValue,
-    tokio::{sync::Mutex, task::JoinHandle, time::sleep},
+    rand::Rng,
+    serde::Deserialize,
+    serde_json::{Map, Value as JsonValue},
+    tokio::{
+        sync::{mpsc, Mutex, Semaphore},
+        task::JoinHandle,
+        time::sleep,
+    },
 };
 
-use crate::config::Script;
+use crate::{
+    cassette::{Cassette, Invocation},
+    config::{self, CatchUp, Concurrency, RunPolicy, Scheduling, Script, When},
+    job_queue::{JobQueue, QueuedJob, QueuedRun, RunState},
+};
 
-const PATH_ENV_NAME: &str = "LUA_PATH";
+/// How many Lua instructions run between checks of a task's deadline.
+const DEADLINE_CHECK_INSTRUCTIONS: u32 = 1000;
+/// Longest wait between retries of a failed task.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Longest the timer sleeps before checking the wall clock again.
+const MAX_TIMER_SLEEP: Duration = Duration::from_secs(60);
+/// How late a run can start before it's reported as held up.
+const LATE_AFTER: Duration = Duration::from_secs(60);
+/// How far the wall clock can move apart from steady time between checks before it counts as
+/// having been changed or the machine having been asleep.
+const CLOCK_JUMP: Duration = Duration::from_secs(60);
+/// Most missed runs of a task caught up on at once.
+const MAX_CATCH_UP_RUNS: usize = 100;
 
 pub struct Scope {
     map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
@@ -53,30 +83,78 @@ impl Scope {
     }
 }
 
+/// The script whose code is currently running, kept in the scope so registered functions can tell
+/// which script called them.
+pub struct ActiveScript(pub Option<PathBuf>);
+
 pub struct Task {
     pub task_name: String,
-    pub params: String,
+    pub params: JsonValue,
+    /// Stops `execute` with an error once it has run this long.
+    pub timeout: Option<Duration>,
+    /// Stops `execute` with an error as soon as it's set.
+    pub abort: Arc<AtomicBool>,
+}
+
+/// How a task run ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TaskOutcome {
+    /// `execute` returned, with its return value converted to JSON.
+    Success(JsonValue),
+    /// `execute` raised an error or its handler couldn't be called.
+    Failed(String),
+    /// `execute` ran past the task's timeout, or was aborted, and was stopped.
+    TimedOut,
+}
+
+/// The record of a single task run.
+#[derive(Clone, Debug)]
+pub struct TaskRun {
+    pub task_name: String,
+    pub duration: Duration,
+    pub outcome: TaskOutcome,
+}
+
+/// A task that can be run, along with the Lua table handling it and the script defining that.
+struct RegisteredTask {
+    handler: String,
+    script: PathBuf,
 }
 
 pub struct TaskManager {
     lua: Arc<Mutex<Lua>>,
     pub scope: Arc<StdMutex<Scope>>,
-    tasks: Vec<String>,
-    lua_path: String,
+    cassette: Arc<StdMutex<Option<Cassette>>>,
+    /// Registered tasks by name.
+    tasks: HashMap<String, RegisteredTask>,
+    /// Handlers whose `setup` has run, along with their script, so each is torn down once.
+    handlers: HashMap<String, PathBuf>,
 }
 
 impl TaskManager {
     pub async fn new() -> Result<Self, Box<dyn Error>> {
-        let lua = Arc::new(Mutex::new(unsafe { Lua::unsafe_new() }));
+        let lua = unsafe { Lua::unsafe_new() };
+        // Scripts print values they read, so `print` has to keep secrets out of stdout too.
+        let print = lua.create_function(|lua, args: LuaMultiValue| {
+            println!("{}", crate::secrets::redact(&print_line(lua, args)?));
+            Ok(())
+        })?;
+        lua.globals().set("print", print)?;
+        let lua = Arc::new(Mutex::new(lua));
+        let mut scope = Scope::new();
+        scope.insert(ActiveScript(None));
 
         Ok(Self {
             lua,
-            tasks: vec![],
-            scope: Arc::new(StdMutex::new(Scope::new())),
-            lua_path: env::var(PATH_ENV_NAME).unwrap_or(String::from("")),
+            tasks: HashMap::new(),
+            handlers: HashMap::new(),
+            scope: Arc::new(StdMutex::new(scope)),
+            cassette: Arc::new(StdMutex::new(None)),
         })
     }
 
+    /// Runs the script and the `setup` of each of its tasks. Registering a script again, such as
+    /// after it changed, tears down its handlers and replaces its definitions in the Lua state.
     pub async fn register_script(
         &mut self,
         contents: &str,
@@ -89,37 +167,170 @@ impl TaskManager {
         //     include_str!("./scripts/script.lua"),
         // )];
 
-        lua.load(contents).exec()?;
+        // Let scripts `require` modules next to them. `LUA_PATH` is only read when the state is
+        // created, so this has to go through `package.path`.
+        let package: LuaTable = lua.globals().get("package")?;
+        let path: String = package.get("path")?;
+        let script_path = format!("{}/?.lua", script.path.parent().unwrap().to_str().unwrap());
+        if !path.split(';').any(|entry| entry == script_path) {
+            package.set("path", format!("{};{}", path, script_path))?;
+        }
 
-        env::set_var(
-            PATH_ENV_NAME,
-            format!(
-                "{};{}/?.lua",
-                self.lua_path,
-                script.path.parent().unwrap().to_str().unwrap()
-            ),
-        );
+        set_active_script(&self.scope, Some(script.path.clone()));
+        let result = (|| -> LuaResult<()> {
+            // Teardown has to run against the old definitions, before the script replaces them.
+            self.handlers.retain(|handler, handler_script| {
+                if handler_script != &script.path {
+                    return true;
+                }
+                call_hook(&lua, handler, "teardown", ());
+                false
+            });
+
+            lua.load(contents).exec()?;
+
+            let mut set_up = HashSet::new();
+            for task in &script.tasks {
+                // Tasks sharing a handler only set it up once.
+                if set_up.insert(task.handler()) {
+                    handler_function(&lua, task.handler(), "setup")?.call::<_, ()>(())?;
+                    self.handlers
+                        .insert(task.handler().to_string(), script.path.clone());
+                }
+                self.tasks.insert(
+                    task.name.clone(),
+                    RegisteredTask {
+                        handler: task.handler().to_string(),
+                        script: script.path.clone(),
+                    },
+                );
+            }
+
+            Ok(())
+        })();
+        set_active_script(&self.scope, None);
+
+        Ok(result?)
+    }
+
+    pub fn has_task(&self, task_name: &str) -> bool {
+        self.tasks.contains_key(task_name)
+    }
+
+    /// The handler a registered task runs.
+    pub fn handler(&self, task_name: &str) -> Option<&str> {
+        self.tasks
+            .get(task_name)
+            .map(|registered| registered.handler.as_str())
+    }
+
+    /// Stops a task from being run, tearing down its handler if no other task uses it. Its
+    /// definitions stay in the Lua state.
+    pub async fn unregister_task(&mut self, task_name: &str) {
+        let Some(registered) = self.tasks.remove(task_name) else {
+            return;
+        };
+        info!("Unregistered task {}", task_name);
+
+        let in_use = self
+            .tasks
+            .values()
+            .any(|task| task.handler == registered.handler);
+        if !in_use {
+            if let Some(script) = self.handlers.remove(&registered.handler) {
+                let lua = self.lua.lock().await;
+                set_active_script(&self.scope, Some(script));
+                call_hook(&lua, &registered.handler, "teardown", ());
+                set_active_script(&self.scope, None);
+            }
+        }
+    }
+
+    /// Tears down every handler, waiting for any running task to finish first.
+    pub async fn shutdown(&mut self) {
+        let lua = self.lua.lock().await;
+        for (handler, script) in self.handlers.drain() {
+            set_active_script(&self.scope, Some(script));
+            call_hook(&lua, &handler, "teardown", ());
+        }
+        set_active_script(&self.scope, None);
+        self.tasks.clear();
+    }
+
+    /// Lua modules that have been `require`d from files, along with their paths.
+    pub async fn modules(&self) -> Result<Vec<(String, PathBuf)>> {
+        let lua = self.lua.lock().await;
+        let package: LuaTable = lua.globals().get("package")?;
+        let loaded: LuaTable = package.get("loaded")?;
+        let search_path: LuaFunction = package.get("searchpath")?;
+        let path: String = package.get("path")?;
 
-        for task in &script.tasks {
-            lua.load(&format!("{}.setup()", task.name)).exec()?;
-            self.tasks.push(task.name.clone());
+        let mut modules = vec![];
+        for pair in loaded.pairs::<LuaValue, LuaValue>() {
+            let (LuaValue::String(name), _) = pair? else {
+                continue;
+            };
+            let name = name.to_str()?.to_string();
+            let file: Option<String> = search_path.call((name.as_str(), path.as_str()))?;
+            if let Some(file) = file {
+                modules.push((name, PathBuf::from(file)));
+            }
         }
 
+        Ok(modules)
+    }
+
+    /// Forgets a loaded module so the next `require` reads it from disk again.
+    pub async fn unload_module(&self, name: &str) -> Result<()> {
+        let lua = self.lua.lock().await;
+        let package: LuaTable = lua.globals().get("package")?;
+        let loaded: LuaTable = package.get("loaded")?;
+        loaded.set(name, LuaNil)?;
         Ok(())
     }
 
+    /// Routes task runs and registered function calls through `cassette` to record or replay
+    /// them.
+    pub fn set_cassette(&mut self, cassette: Cassette) {
+        *self.cassette.lock().unwrap() = Some(cassette);
+    }
+
     pub async fn register_function<F>(&mut self, name: &str, function: F) -> Result<()>
+    where
+        F: Fn(&mut Scope, JsonValue) -> JsonValue + Send + Sync + 'static,
+    {
+        self.expose(name, function, true).await
+    }
+
+    /// Registers a function that always runs live, even when replaying, and is never recorded.
+    /// For functions that read or change the service's own state, such as secrets and jobs.
+    pub async fn register_live_function<F>(&mut self, name: &str, function: F) -> Result<()>
+    where
+        F: Fn(&mut Scope, JsonValue) -> JsonValue + Send + Sync + 'static,
+    {
+        self.expose(name, function, false).await
+    }
+
+    async fn expose<F>(&mut self, name: &str, function: F, recorded: bool) -> Result<()>
     where
         F: Fn(&mut Scope, JsonValue) -> JsonValue + Send + Sync + 'static,
     {
         let lua = self.lua.lock().await;
         let function = Arc::new(function);
         let scope = self.scope.clone();
+        let cassette = recorded.then(|| self.cassette.clone());
+        let function_name = name.to_string();
 
         let lua_function = lua.create_function(move |lua_ctx, params: mlua::Value| {
             let json_params: JsonValue = lua_ctx.from_value(params)?;
             let mut scope = scope.lock().unwrap();
-            let result = function(&mut scope, json_params);
+            let mut cassette = cassette.as_ref().map(|cassette| cassette.lock().unwrap());
+            let result = match cassette.as_mut().and_then(|cassette| cassette.as_mut()) {
+                Some(cassette) => cassette.call(&function_name, json_params, |params| {
+                    function(&mut scope, params)
+                }),
+                None => function(&mut scope, json_params),
+            };
             lua_ctx.to_value(&result).map_err(LuaError::external)
         })?;
 
@@ -128,95 +339,2266 @@ impl TaskManager {
         Ok(())
     }
 
-    pub async fn schedule(&mut self, task: Task) -> Result<(), Box<dyn Error>> {
-        if !self.tasks.iter().any(|i| i == &task.task_name) {
-            panic!("No task");
-        }
+    /// Runs a task in the background. The handler's `execute` is looked up in the Lua globals
+    /// and called with the task's params, so nothing is evaluated as code. Afterwards the
+    /// handler's `on_success`, `on_error` or `on_timeout` is called if it has one.
+    pub async fn schedule(&mut self, task: Task) -> Result<JoinHandle<TaskRun>, Box<dyn Error>> {
+        let Some(registered) = self.tasks.get(&task.task_name) else {
+            return Err(format!("task {} is no longer registered", task.task_name).into());
+        };
+        let handler = registered.handler.clone();
+        let script_path = registered.script.clone();
 
         let task_lua = self.lua.clone();
-        tokio::spawn(async move {
-            let params = {
-                if task.params.is_empty() {
-                    String::from("{}")
-                } else {
-                    task.params
+        let scope = self.scope.clone();
+        let cassette = self.cassette.clone();
+        Ok(tokio::spawn(async move {
+            let lua = task_lua.lock().await;
+            set_active_script(&scope, Some(script_path));
+            let started = Utc::now();
+            if let Some(cassette) = cassette.lock().unwrap().as_mut() {
+                cassette.record_run(Invocation {
+                    task: task.task_name.clone(),
+                    handler: handler.clone(),
+                    params: task.params.clone(),
+                    started,
+                });
+            }
+            let timer = Instant::now();
+            let timed_out = Arc::new(AtomicBool::new(false));
+
+            set_interrupt(
+                &lua,
+                task.timeout.map(|timeout| timer + timeout),
+                task.abort,
+                timed_out.clone(),
+            );
+            let result = execute(&lua, &handler, &task.params).and_then(|value| {
+                let json: JsonValue = lua.from_value(value.clone())?;
+                Ok((json, value))
+            });
+            lua.remove_hook();
+
+            let outcome = match result {
+                Ok((json, value)) => {
+                    call_hook(&lua, &handler, "on_success", value);
+                    TaskOutcome::Success(json)
                 }
-            };
-            match task_lua
-                .lock()
-                .await
-                .load(&format!("pcall({}.execute, {})", task.task_name, params))
-                .exec()
-            {
-                Ok(result) => {
-                    debug!("{:?}", result);
+                Err(_) if timed_out.load(Ordering::Relaxed) => {
+                    call_hook(&lua, &handler, "on_timeout", ());
+                    TaskOutcome::TimedOut
                 }
                 Err(e) => {
-                    error!("{}", e);
+                    let message = e.to_string();
+                    call_hook(&lua, &handler, "on_error", message.as_str());
+                    TaskOutcome::Failed(message)
                 }
+            };
+
+            set_active_script(&scope, None);
+            TaskRun {
+                task_name: task.task_name,
+                duration: timer.elapsed(),
+                outcome,
             }
+        }))
+    }
+}
+
+/// Formats arguments the way Lua's `print` does, with `tostring` and separated by tabs.
+fn print_line<'lua>(lua: &'lua Lua, args: LuaMultiValue<'lua>) -> LuaResult<String> {
+    let tostring: LuaFunction = lua.globals().get("tostring")?;
+    let parts = args
+        .into_iter()
+        .map(|arg| {
+            Ok(tostring
+                .call::<_, LuaString>(arg)?
+                .to_string_lossy()
+                .into_owned())
+        })
+        .collect::<LuaResult<Vec<_>>>()?;
+    Ok(parts.join("\t"))
+}
+
+/// Looks up `name` on the handler table without running any Lua code.
+fn handler_function<'lua>(
+    lua: &'lua Lua,
+    handler: &str,
+    name: &str,
+) -> LuaResult<LuaFunction<'lua>> {
+    let LuaValue::Table(table) = lua.globals().get::<_, LuaValue>(handler)? else {
+        return Err(LuaError::RuntimeError(format!(
+            "{} is not a table",
+            handler
+        )));
+    };
+    match table.get::<_, LuaValue>(name)? {
+        LuaValue::Function(function) => Ok(function),
+        _ => Err(LuaError::RuntimeError(format!(
+            "{}.{} is not a function",
+            handler, name
+        ))),
+    }
+}
+
+/// Calls an optional hook on the handler table. Hooks report their own failures, since they run
+/// after the outcome of a task is already decided.
+fn call_hook<'lua>(lua: &'lua Lua, handler: &str, name: &str, args: impl IntoLuaMulti<'lua>) {
+    let LuaValue::Table(table) = lua.globals().get::<_, LuaValue>(handler).unwrap_or(LuaNil) else {
+        return;
+    };
+    let result = match table.get::<_, LuaValue>(name) {
+        Ok(LuaValue::Function(function)) => function.call::<_, ()>(args),
+        Ok(LuaNil) => return,
+        Ok(_) => Err(LuaError::RuntimeError(format!(
+            "{}.{} is not a function",
+            handler, name
+        ))),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        error!("{}.{} failed: {}", handler, name, e);
+    }
+}
+
+/// Calls the handler's `execute` with `params`.
+fn execute<'lua>(lua: &'lua Lua, handler: &str, params: &JsonValue) -> LuaResult<LuaValue<'lua>> {
+    let params = match params {
+        JsonValue::Null => LuaValue::Table(lua.create_table()?),
+        params => lua.to_value(params)?,
+    };
+    handler_function(lua, handler, "execute")?.call(params)
+}
+
+/// Makes Lua raise an error once `deadline` passes or `abort` is set, flagging `timed_out`. Code
+/// blocked in a registered function, such as `http_get`, is only stopped once that function
+/// returns.
+fn set_interrupt(
+    lua: &Lua,
+    deadline: Option<Instant>,
+    abort: Arc<AtomicBool>,
+    timed_out: Arc<AtomicBool>,
+) {
+    lua.set_hook(
+        LuaHookTriggers::new().every_nth_instruction(DEADLINE_CHECK_INSTRUCTIONS),
+        move |_lua, _debug| {
+            let message = if abort.load(Ordering::Relaxed) {
+                "task aborted"
+            } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                "task timed out"
+            } else {
+                return Ok(());
+            };
+            timed_out.store(true, Ordering::Relaxed);
+            Err(LuaError::RuntimeError(String::from(message)))
+        },
+    );
+}
+
+fn set_active_script(scope: &StdMutex<Scope>, script: Option<PathBuf>) {
+    scope.lock().unwrap().insert(ActiveScript(script));
+}
+
+/// When a task runs. Every schedule syntax the config accepts is parsed to one of these.
+#[derive(Clone, Debug)]
+pub enum TaskSchedule {
+    /// Cron expression in a timezone, which phrases such as `weekdays at 08:30` are turned into.
+    Cron(Box<Schedule>, Tz),
+    /// Runs this long after the last run.
+    Interval(Duration),
+    /// Runs once.
+    Once(DateTime<Utc>),
+}
+
+impl TaskSchedule {
+    pub fn new(when: &When, timezone: Tz) -> Result<Self> {
+        match when {
+            When::Cron(cron) => Ok(Self::Cron(
+                Box::new(
+                    Schedule::from_str(cron).with_context(|| format!("invalid cron {:?}", cron))?,
+                ),
+                timezone,
+            )),
+            When::Every(every) => match parse_interval(every) {
+                Some(interval) => Ok(Self::Interval(interval)),
+                None => Ok(Self::Cron(
+                    Box::new(Schedule::from_str(&phrase_to_cron(every)?)?),
+                    timezone,
+                )),
+            },
+            When::At(at) => parse_at(at, timezone).map(Self::Once),
+        }
+    }
+
+    /// The first time the task is due after `time`, unless it has no runs left.
+    pub fn after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Cron(cron, timezone) => cron
+                .after(&time.with_timezone(timezone))
+                .next()
+                .map(|next| next.with_timezone(&Utc)),
+            Self::Interval(interval) => TimeDelta::from_std(*interval)
+                .ok()
+                .and_then(|interval| time.checked_add_signed(interval)),
+            Self::Once(at) => (*at > time).then_some(*at),
+        }
+    }
+
+    /// Every time the task is due after `time`.
+    pub fn upcoming(&self, time: DateTime<Utc>) -> impl Iterator<Item = DateTime<Utc>> + '_ {
+        iter::successors(self.after(time), |time| self.after(*time))
+    }
+}
+
+/// Parses an interval such as `15m` or `1h30m`. Zero isn't an interval.
+fn parse_interval(interval: &str) -> Option<Duration> {
+    let mut rest = interval.trim();
+    let mut seconds: u64 = 0;
+    while !rest.is_empty() {
+        let (number, unit) = rest.split_at(rest.find(|c: char| !c.is_ascii_digit())?);
+        let unit_seconds = match unit.chars().next()? {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        seconds = seconds.checked_add(number.parse::<u64>().ok()?.checked_mul(unit_seconds)?)?;
+        rest = unit[1..].trim_start();
+    }
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// Turns a phrase such as `weekdays at 08:30`, `monday and friday at 9:00` or `daily at 9am` into
+/// a cron expression. A leading `every` is ignored, so `every day at 09:00` reads naturally too.
+fn phrase_to_cron(phrase: &str) -> Result<String> {
+    let phrase = phrase.trim().to_lowercase();
+    let Some((days, time)) = phrase.split_once(" at ") else {
+        bail!(
+            "invalid schedule {:?}, expected an interval such as 15m or a phrase such as weekdays at 08:30",
+            phrase
+        );
+    };
+
+    let (hour, minute) = parse_time(time.trim())
+        .with_context(|| format!("invalid time {:?}, expected one such as 08:30 or 9am", time))?;
+
+    let mut weekdays = vec![];
+    for day in days
+        .split([',', ' '])
+        .filter(|day| !matches!(*day, "" | "and" | "every"))
+    {
+        weekdays.push(match day.strip_suffix('s').unwrap_or(day) {
+            "day" | "daily" => "*",
+            "weekday" => "Mon-Fri",
+            "weekend" => "Sat,Sun",
+            "monday" => "Mon",
+            "tuesday" => "Tue",
+            "wednesday" => "Wed",
+            "thursday" => "Thu",
+            "friday" => "Fri",
+            "saturday" => "Sat",
+            "sunday" => "Sun",
+            _ => bail!("invalid day {:?} in schedule {:?}", day, phrase),
         });
+    }
+    if weekdays.is_empty() {
+        bail!("schedule {:?} has no days", phrase);
+    }
+
+    Ok(format!(
+        "0 {} {} * * {} *",
+        minute,
+        hour,
+        weekdays.join(",")
+    ))
+}
+
+/// Parses a 24-hour time such as `08:30`, or a 12-hour one such as `9am` or `5:30pm`, into the hour
+/// and minute.
+fn parse_time(time: &str) -> Option<(u32, u32)> {
+    let (time, offset) = match (time.strip_suffix("am"), time.strip_suffix("pm")) {
+        (Some(time), _) => (time.trim_end(), Some(0)),
+        (_, Some(time)) => (time.trim_end(), Some(12)),
+        _ => (time, None),
+    };
+    let (hour, minute) = match (time.split_once(':'), offset) {
+        (Some((hour, minute)), _) => (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?),
+        (None, Some(_)) => (time.parse::<u32>().ok()?, 0),
+        (None, None) => return None,
+    };
+    if minute >= 60 {
+        return None;
+    }
+
+    match offset {
+        // 12am is midnight and 12pm is noon.
+        Some(offset) if (1..=12).contains(&hour) => Some((hour % 12 + offset, minute)),
+        Some(_) => None,
+        None => (hour < 24).then_some((hour, minute)),
+    }
+}
+
+/// Parses a date and time such as `2026-11-01T09:00` in `timezone`, or one with an offset.
+fn parse_at(at: &str, timezone: Tz) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(at) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let local = ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(at, format).ok())
+        .with_context(|| {
+            format!(
+                "invalid date and time {:?}, expected one such as 2026-11-01T09:00",
+                at
+            )
+        })?;
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .with_context(|| format!("{} doesn't exist in {}", at, timezone))
+}
+
+/// Source of the current time for the scheduler, so schedules can be tested without waiting.
+/// Shared with its handles so jobs are scheduled from the same time.
+pub trait Clock: Clone {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Time that never jumps and, like `Instant`, doesn't count time the machine was asleep.
+    fn steady(&self) -> Instant;
+
+    /// Waits until `time`, returning straight away if it has passed.
+    fn sleep_until(&self, time: DateTime<Utc>) -> impl Future<Output = ()> + Send;
+}
+
+#[derive(Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
 
+    fn steady(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, time: DateTime<Utc>) {
+        // Timers don't count time spent asleep, so wake up now and then to check the wall clock.
+        while let Ok(left) = (time - Utc::now()).to_std() {
+            if left.is_zero() {
+                break;
+            }
+            sleep(left.min(MAX_TIMER_SLEEP)).await;
+        }
+    }
+}
+
+/// The attempt a run is waiting on. Kept outside the run so it can still be waited for once the
+/// run itself has been aborted.
+type Attempt = Arc<Mutex<Option<JoinHandle<TaskRun>>>>;
+
+/// A run that has been started, checked by the watchdog.
+struct ActiveRun {
+    /// Id of the run's entry in the job queue.
+    id: String,
+    task_name: String,
+    /// When the run is aborted if it hasn't finished.
+    deadline: Option<DateTime<Utc>>,
+    abort: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+    in_flight: Attempt,
+}
+
+/// When each task was last due, kept on disk so runs missed while the service was down can be
+/// caught up.
+struct LastRuns {
+    path: PathBuf,
+    runs: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl LastRuns {
+    fn load(path: &Path) -> Result<Self> {
+        let runs = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("invalid schedule state in {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("unable to read schedule state {}", path.display()))
+            }
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            runs,
+        })
+    }
+
+    fn get(&self, task_name: &str) -> Option<DateTime<Utc>> {
+        self.runs.get(task_name).copied()
+    }
+
+    /// Records that `task_name` was due at `time` and saves the state.
+    fn set(&mut self, task_name: &str, time: DateTime<Utc>) {
+        self.runs.insert(task_name.to_string(), time);
+        if let Err(e) = self.save() {
+            error!("Unable to save schedule state: {:#}", e);
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        // Write then rename so a crash never leaves a half written file behind.
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_string_pretty(&self.runs)?)
+            .with_context(|| format!("unable to write {}", temp.display()))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("unable to write {}", self.path.display()))?;
         Ok(())
     }
 }
 
-pub struct Scheduler {
-    scheduled: HashMap<String, JoinHandle<()>>,
-    tasks: Vec<(String, Schedule)>,
+/// When a task or job is next due. Entries from before a task was last changed, or for cancelled
+/// jobs, are skipped.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Fire {
+    time: DateTime<Utc>,
+    task_name: String,
+    job: Option<String>,
+    generation: u64,
+}
+
+/// What `schedule_task` is called with.
+#[derive(Deserialize)]
+pub struct JobSpec {
+    pub task: String,
+    #[serde(flatten)]
+    pub when: JobWhen,
+    /// Merged over the task's own params.
+    #[serde(default)]
+    pub params: Map<String, JsonValue>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobWhen {
+    /// Date and time in the task's timezone, like a task's `at`.
+    At(String),
+    /// Interval from now such as `2h`.
+    After(String),
+    /// Cron expression in the task's timezone.
+    Cron(String),
+}
+
+/// Runs of a configured task added at runtime with `schedule_task`.
+struct Job {
+    id: String,
+    task_name: String,
+    /// What `schedule` was parsed from, for saving the job.
+    when: When,
+    schedule: TaskSchedule,
+    params: Map<String, JsonValue>,
+    last_run: DateTime<Utc>,
+}
+
+impl Job {
+    fn queued(&self) -> QueuedJob {
+        QueuedJob {
+            task_name: self.task_name.clone(),
+            when: self.when.clone(),
+            params: self.params.clone(),
+            last_run: self.last_run,
+        }
+    }
+}
+
+enum JobRequest {
+    Schedule(Job),
+    Cancel(String),
+}
+
+/// What handles need to know about the scheduler to check requests before sending them.
+#[derive(Default)]
+struct HandleState {
+    timezones: HashMap<String, Tz>,
+    jobs: HashSet<String>,
+}
+
+/// Schedules and cancels jobs from outside the scheduler, such as from exposed functions. Kept
+/// in the `Scope`.
+#[derive(Clone)]
+pub struct SchedulerHandle<C: Clock = SystemClock> {
+    clock: C,
+    requests: mpsc::UnboundedSender<JobRequest>,
+    state: Arc<StdMutex<HandleState>>,
+}
+
+impl<C: Clock> SchedulerHandle<C> {
+    /// Schedules runs of a task, returning the new job's id.
+    pub fn schedule(&self, spec: JobSpec) -> Result<String> {
+        let mut state = self.state.lock().unwrap();
+        let Some(&timezone) = state.timezones.get(&spec.task) else {
+            bail!("no task named {}", spec.task);
+        };
+
+        let now = self.clock.now();
+        let when = match spec.when {
+            JobWhen::At(at) => When::At(at),
+            JobWhen::Cron(cron) => When::Cron(cron),
+            JobWhen::After(after) => parse_interval(&after)
+                .and_then(|after| TimeDelta::from_std(after).ok())
+                .and_then(|after| now.checked_add_signed(after))
+                .map(|at| When::At(at.to_rfc3339()))
+                .with_context(|| {
+                    format!("invalid interval {:?}, expected one such as 2h", after)
+                })?,
+        };
+        let schedule = TaskSchedule::new(&when, timezone)?;
+        if schedule.after(now).is_none() {
+            bail!("the job would never run");
+        }
+
+        let id = format!("{}-{:08x}", spec.task, rand::random::<u32>());
+        self.requests
+            .send(JobRequest::Schedule(Job {
+                id: id.clone(),
+                task_name: spec.task,
+                when,
+                schedule,
+                params: spec.params,
+                last_run: now,
+            }))
+            .ok()
+            .context("the scheduler has stopped")?;
+        state.jobs.insert(id.clone());
+
+        Ok(id)
+    }
+
+    /// Cancels a job's remaining runs, returning whether it had any.
+    pub fn cancel(&self, id: &str) -> bool {
+        let pending = self.state.lock().unwrap().jobs.remove(id);
+        if pending {
+            let _ = self.requests.send(JobRequest::Cancel(id.to_string()));
+        }
+        pending
+    }
+}
+
+struct ScheduledTask {
+    task: config::Task,
+    schedule: TaskSchedule,
+    timezone: Tz,
+    generation: u64,
+}
+
+/// Runs tasks when they're due. Every task's next run is kept in one queue ordered by time, and
+/// the service waits on the earliest with a single timer.
+pub struct Scheduler<C: Clock = SystemClock> {
+    clock: C,
+    tasks: HashMap<String, ScheduledTask>,
+    queue: BinaryHeap<Reverse<Fire>>,
+    /// Bumped whenever a task is scheduled, to tell its current entry in `queue` from old ones.
+    generation: u64,
+    /// Since when the service has been running without the clock jumping or the machine sleeping.
+    /// Runs due before then were missed, later ones were only held up.
+    awake_since: DateTime<Utc>,
+    /// The wall clock and steady time when the clock was last checked.
+    last_check: (DateTime<Utc>, Instant),
+    scheduling: Scheduling,
+    last_runs: LastRuns,
+    jobs: HashMap<String, Job>,
+    job_queue: Arc<StdMutex<JobQueue>>,
+    requests: mpsc::UnboundedReceiver<JobRequest>,
+    handle: SchedulerHandle<C>,
+    running: Vec<ActiveRun>,
+    /// One permit per task, held while it runs unless its runs are allowed in parallel.
+    permits: HashMap<String, Arc<Semaphore>>,
 }
 
 impl Scheduler {
-    pub fn new() -> Result<Scheduler> {
+    pub fn new(scheduling: &Scheduling) -> Result<Scheduler> {
+        Scheduler::with_clock(scheduling, SystemClock)
+    }
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn with_clock(scheduling: &Scheduling, clock: C) -> Result<Self> {
+        let (sender, requests) = mpsc::unbounded_channel();
+        let now = clock.now();
+        let steady = clock.steady();
         Ok(Self {
-            scheduled: HashMap::new(),
-            tasks: vec![],
+            clock: clock.clone(),
+            tasks: HashMap::new(),
+            queue: BinaryHeap::new(),
+            generation: 0,
+            awake_since: now,
+            last_check: (now, steady),
+            scheduling: scheduling.clone(),
+            last_runs: LastRuns::load(&scheduling.state)?,
+            jobs: HashMap::new(),
+            job_queue: Arc::new(StdMutex::new(JobQueue::load(&scheduling.jobs)?)),
+            requests,
+            handle: SchedulerHandle {
+                clock,
+                requests: sender,
+                state: Default::default(),
+            },
+            running: vec![],
+            permits: HashMap::new(),
         })
     }
 
-    pub fn register_task(&mut self, task_name: String, schedule: String) -> Result<()> {
-        self.tasks.push((task_name, Schedule::from_str(&schedule)?));
+    pub fn handle(&self) -> SchedulerHandle<C> {
+        self.handle.clone()
+    }
+
+    pub fn register_task(&mut self, task: &config::Task) -> Result<()> {
+        let timezone = task.timezone(&self.scheduling);
+        let schedule = TaskSchedule::new(&task.when, timezone)
+            .with_context(|| format!("task {} has an invalid schedule", task.name))?;
+        self.insert(task.clone(), schedule, timezone);
+        Ok(())
+    }
+
+    /// Replaces the registered tasks with `tasks`. Tasks whose config or timezone changed are
+    /// rescheduled and removed tasks won't run again. Nothing changes if any schedule is invalid.
+    pub fn sync_tasks(&mut self, tasks: &[config::Task], scheduling: &Scheduling) -> Result<()> {
+        let mut synced = vec![];
+        for task in tasks {
+            let timezone = task.timezone(scheduling);
+            let schedule = TaskSchedule::new(&task.when, timezone)
+                .with_context(|| format!("task {} has an invalid schedule", task.name))?;
+            synced.push((task.clone(), schedule, timezone));
+        }
+
+        self.tasks
+            .retain(|task_name, _| tasks.iter().any(|task| &task.name == task_name));
+        self.permits
+            .retain(|task_name, _| tasks.iter().any(|task| &task.name == task_name));
+        self.handle
+            .state
+            .lock()
+            .unwrap()
+            .timezones
+            .retain(|task_name, _| tasks.iter().any(|task| &task.name == task_name));
+
+        for (task, schedule, timezone) in synced {
+            let unchanged = self
+                .tasks
+                .get(&task.name)
+                .is_some_and(|scheduled| scheduled.task == task && scheduled.timezone == timezone);
+            if !unchanged {
+                self.insert(task, schedule, timezone);
+            }
+        }
+        self.scheduling = scheduling.clone();
+
         Ok(())
     }
 
-    pub fn run(&mut self, task_manager: Arc<Mutex<TaskManager>>) -> Result<()> {
-        for (task_name, cron) in &self.tasks {
-            let mut schedule = false;
+    /// Queues the next run of a task after it last ran, replacing any it already had.
+    fn insert(&mut self, task: config::Task, schedule: TaskSchedule, timezone: Tz) {
+        let from = match self.last_runs.get(&task.name) {
+            Some(last_run) => last_run,
+            None => {
+                let now = self.clock.now();
+                self.last_runs.set(&task.name, now);
+                now
+            }
+        };
+
+        self.generation += 1;
+        match schedule.after(from) {
+            Some(next) => self.queue.push(Reverse(Fire {
+                time: next,
+                task_name: task.name.clone(),
+                job: None,
+                generation: self.generation,
+            })),
+            None => info!("Task {} has no runs left", task.name),
+        }
+        self.handle
+            .state
+            .lock()
+            .unwrap()
+            .timezones
+            .insert(task.name.clone(), timezone);
+
+        self.tasks.insert(
+            task.name.clone(),
+            ScheduledTask {
+                task,
+                schedule,
+                timezone,
+                generation: self.generation,
+            },
+        );
+    }
+
+    /// Picks up the jobs and unfinished runs kept in the job queue, once the tasks they belong to
+    /// are registered. A run that was in progress counts as a failed attempt, so it's only run
+    /// again if its task has retries left.
+    pub fn resume(&mut self, task_manager: Arc<Mutex<TaskManager>>) {
+        let (jobs, runs): (Vec<_>, Vec<_>) = {
+            let job_queue = self.job_queue.lock().unwrap();
+            (
+                job_queue
+                    .jobs()
+                    .map(|(id, job)| (id.clone(), job.clone()))
+                    .collect(),
+                job_queue
+                    .runs()
+                    .map(|(id, run)| (id.clone(), run.clone()))
+                    .collect(),
+            )
+        };
 
-            if let Some(task) = self.scheduled.get(task_name) {
-                if task.is_finished() {
-                    debug!("Task finished: {}", task_name);
-                    schedule = true;
+        for (id, queued) in jobs {
+            let Some(scheduled) = self.tasks.get(&queued.task_name) else {
+                warn!(
+                    "Dropping job {}, task {} no longer exists",
+                    id, queued.task_name
+                );
+                self.remove_job(&id);
+                continue;
+            };
+            match TaskSchedule::new(&queued.when, scheduled.timezone) {
+                Ok(schedule) => self.add_job(Job {
+                    id,
+                    task_name: queued.task_name,
+                    when: queued.when,
+                    schedule,
+                    params: queued.params,
+                    last_run: queued.last_run,
+                }),
+                Err(e) => {
+                    warn!("Dropping job {}: {:#}", id, e);
+                    self.remove_job(&id);
                 }
-            } else {
-                schedule = true;
             }
+        }
 
-            if schedule {
-                let task_manager_cloned = task_manager.clone();
-                let duration = (cron.upcoming(Utc).next().unwrap() - Utc::now()).num_milliseconds();
-                let task_name = task_name.clone();
-                debug!(
-                    "Scheduling {} task to run in {} millis",
-                    task_name, duration
+        let now = self.clock.now();
+        for (id, run) in runs {
+            let Some(scheduled) = self.tasks.get(&run.task_name) else {
+                warn!(
+                    "Dropping run {}, task {} no longer exists",
+                    id, run.task_name
                 );
-                self.scheduled.insert(
-                    task_name.clone(),
-                    tokio::spawn(async move {
-                        sleep(Duration::from_millis(duration as u64)).await;
-                        let mut task_manager = task_manager_cloned.lock().await;
-                        task_manager
-                            .schedule(Task {
-                                task_name: task_name.clone(),
-                                params: String::from(""),
-                            })
-                            .await
-                            .unwrap();
-                    }),
+                self.job_queue.lock().unwrap().remove_run(&id);
+                continue;
+            };
+            let mut task = scheduled.task.clone();
+            if run.attempts > task.policy.retries {
+                error!(
+                    "Task {} was stopped during its last attempt, giving up until it's next due",
+                    task.name
                 );
+                self.job_queue.lock().unwrap().remove_run(&id);
+                continue;
             }
+
+            info!(
+                "Resuming run {} of task {} after {} attempt(s)",
+                id, task.name, run.attempts
+            );
+            task.params = run.params;
+            self.start_run(task, task_manager.clone(), now, Some((id, run.attempts)));
         }
+    }
 
-        Ok(())
+    /// Cancels every pending run, along with retries and queued runs. Attempts in progress are
+    /// told to abort and waited for. Those that still succeed, such as one blocked in a registered
+    /// function until it returned, are done with. The rest stay in the job queue and are resumed
+    /// on the next start.
+    pub async fn stop(&mut self) {
+        self.queue.clear();
+        for run in self.running.drain(..) {
+            run.abort.store(true, Ordering::Relaxed);
+            run.handle.abort();
+            // Once the abort lands the run no longer holds its attempt.
+            let _ = run.handle.await;
+
+            let Some(attempt) = run.in_flight.lock().await.take() else {
+                continue;
+            };
+            match attempt.await {
+                Ok(finished) => {
+                    log_run(&finished);
+                    if matches!(finished.outcome, TaskOutcome::Success(_)) {
+                        self.job_queue.lock().unwrap().remove_run(&run.id);
+                    }
+                }
+                Err(e) => error!("Task {} panicked: {}", run.task_name, e),
+            }
+        }
+    }
+
+    /// When the scheduler next has something to do, which is a task being due or a run reaching
+    /// its `max_runtime`.
+    pub fn next_wake(&self) -> Option<DateTime<Utc>> {
+        let next_fire = self.queue.peek().map(|Reverse(fire)| fire.time);
+        let next_deadline = self
+            .running
+            .iter()
+            .filter(|run| !run.abort.load(Ordering::Relaxed))
+            .filter_map(|run| run.deadline)
+            .min();
+        next_fire.into_iter().chain(next_deadline).min()
+    }
+
+    /// Waits until `next_wake`, or forever if there's nothing to do. Jobs scheduled or cancelled
+    /// through handles in the meantime are handled straight away.
+    pub async fn wait(&mut self) {
+        let next_wake = self.next_wake();
+        let clock = &self.clock;
+        tokio::select! {
+            _ = async {
+                match next_wake {
+                    Some(time) => clock.sleep_until(time).await,
+                    None => std::future::pending().await,
+                }
+            } => {}
+            Some(request) = self.requests.recv() => self.handle_request(request),
+        }
+    }
+
+    fn handle_request(&mut self, request: JobRequest) {
+        match request {
+            JobRequest::Schedule(mut job) => {
+                job.last_run = self.clock.now();
+                info!("Scheduled job {} of task {}", job.id, job.task_name);
+                self.add_job(job);
+            }
+            JobRequest::Cancel(id) => {
+                if self.jobs.contains_key(&id) {
+                    info!("Cancelled job {}", id);
+                }
+                self.remove_job(&id);
+            }
+        }
+    }
+
+    /// Queues the next run of a job after it last ran and saves it.
+    fn add_job(&mut self, job: Job) {
+        let Some(next) = job.schedule.after(job.last_run) else {
+            info!("Job {} has no runs left", job.id);
+            self.remove_job(&job.id);
+            return;
+        };
+
+        self.queue.push(Reverse(Fire {
+            time: next,
+            task_name: job.task_name.clone(),
+            job: Some(job.id.clone()),
+            generation: 0,
+        }));
+        self.job_queue
+            .lock()
+            .unwrap()
+            .add_job(&job.id, job.queued());
+        self.handle
+            .state
+            .lock()
+            .unwrap()
+            .jobs
+            .insert(job.id.clone());
+        self.jobs.insert(job.id.clone(), job);
+    }
+
+    fn remove_job(&mut self, id: &str) {
+        self.jobs.remove(id);
+        self.job_queue.lock().unwrap().remove_job(id);
+        self.handle.state.lock().unwrap().jobs.remove(id);
+    }
+
+    /// Starts every run that's due and aborts runs past their `max_runtime`.
+    pub fn fire(&mut self, task_manager: Arc<Mutex<TaskManager>>) {
+        let now = self.clock.now();
+        self.watch_runs(now);
+
+        for (task, runs) in self.take_due(now) {
+            for _ in 0..runs {
+                self.start_run(task.clone(), task_manager.clone(), now, None);
+            }
+        }
+    }
+
+    /// Starts a run of `task` following its concurrency policy, keeping it in the job queue until
+    /// it finishes. `resumed` has the id and attempts so far of a run from before a restart.
+    fn start_run(
+        &mut self,
+        task: config::Task,
+        task_manager: Arc<Mutex<TaskManager>>,
+        now: DateTime<Utc>,
+        resumed: Option<(String, u32)>,
+    ) {
+        let (id, attempts) = resumed.unwrap_or_else(|| {
+            let id = format!("{}-{:08x}", task.name, rand::random::<u32>());
+            (id, 0)
+        });
+        let permit = self
+            .permits
+            .entry(task.name.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(1)))
+            .clone();
+
+        let held = match task.policy.concurrency {
+            Concurrency::Skip => match permit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    info!(
+                        "Skipping task {}, its previous run is still going",
+                        task.name
+                    );
+                    self.job_queue.lock().unwrap().remove_run(&id);
+                    return;
+                }
+            },
+            Concurrency::Queue | Concurrency::Parallel => None,
+        };
+        let queue = (task.policy.concurrency == Concurrency::Queue).then_some(permit);
+
+        self.job_queue.lock().unwrap().add_run(
+            &id,
+            QueuedRun {
+                task_name: task.name.clone(),
+                params: task.params.clone(),
+                state: RunState::Waiting,
+                attempts,
+                started: now,
+            },
+        );
+        let entry = RunEntry {
+            id: id.clone(),
+            job_queue: self.job_queue.clone(),
+            attempts,
+        };
+
+        let task_name = task.name.clone();
+        let deadline = task.policy.max_runtime.and_then(|max_runtime| {
+            TimeDelta::try_seconds(max_runtime as i64)
+                .and_then(|max_runtime| now.checked_add_signed(max_runtime))
+        });
+        let abort = Arc::new(AtomicBool::new(false));
+        let abort_cloned = abort.clone();
+        let in_flight = Attempt::default();
+        let in_flight_cloned = in_flight.clone();
+
+        let handle = tokio::spawn(async move {
+            let _permit = match queue {
+                Some(permit) => permit.acquire_owned().await.ok(),
+                None => held,
+            };
+            run_with_retries(task, task_manager, abort_cloned, entry, in_flight_cloned).await;
+        });
+
+        self.running.push(ActiveRun {
+            id,
+            task_name,
+            deadline,
+            abort,
+            handle,
+            in_flight,
+        });
+    }
+
+    /// Takes the tasks and jobs due by `now` off the queue, queueing their next runs, along with
+    /// how many times each should run.
+    fn take_due(&mut self, now: DateTime<Utc>) -> Vec<(config::Task, usize)> {
+        self.check_clock(now);
+        let mut due = vec![];
+
+        while self
+            .queue
+            .peek()
+            .is_some_and(|Reverse(fire)| fire.time <= now)
+        {
+            let Reverse(fire) = self.queue.pop().unwrap();
+            let next = match fire.job {
+                Some(_) => self.take_job(fire, now, &mut due),
+                None => self.take_task(fire, now, &mut due),
+            };
+            if let Some(next) = next {
+                self.queue.push(Reverse(next));
+            }
+        }
+
+        due
+    }
+
+    fn take_task(
+        &mut self,
+        fire: Fire,
+        now: DateTime<Utc>,
+        due: &mut Vec<(config::Task, usize)>,
+    ) -> Option<Fire> {
+        let scheduled = self.tasks.get(&fire.task_name)?;
+        if scheduled.generation != fire.generation {
+            return None;
+        }
+
+        let next_from = if fire.time < self.awake_since {
+            // The service was down or the machine was asleep, so leave this run and any others
+            // since the last one to the catch-up policy.
+            let runs = catch_up(
+                &mut self.last_runs,
+                &scheduled.task,
+                &scheduled.schedule,
+                now,
+            );
+            push_catch_up(due, scheduled.task.clone(), runs);
+            now
+        } else {
+            // Runs that came due while the service was held up, such as by a reload, are folded
+            // into this one.
+            warn_if_late(&fire, now);
+            self.last_runs.set(&fire.task_name, now);
+            due.push((scheduled.task.clone(), 1));
+            now
+        };
+
+        let Some(time) = scheduled.schedule.after(next_from) else {
+            info!("Task {} has no runs left", fire.task_name);
+            return None;
+        };
+        Some(Fire { time, ..fire })
+    }
+
+    fn take_job(
+        &mut self,
+        fire: Fire,
+        now: DateTime<Utc>,
+        due: &mut Vec<(config::Task, usize)>,
+    ) -> Option<Fire> {
+        let id = fire.job.as_deref()?;
+        let missed = fire.time < self.awake_since;
+        let job = self.jobs.get_mut(id)?;
+
+        let next = match self.tasks.get(&job.task_name) {
+            Some(scheduled) => {
+                let mut task = scheduled.task.clone();
+                task.params.extend(job.params.clone());
+                job.last_run = if missed {
+                    let runs = missed_runs(&task, &job.schedule, job.last_run, now);
+                    push_catch_up(due, task, runs);
+                    now
+                } else {
+                    warn_if_late(&fire, now);
+                    due.push((task, 1));
+                    now
+                };
+                self.job_queue
+                    .lock()
+                    .unwrap()
+                    .set_last_run(id, job.last_run);
+                job.schedule.after(job.last_run)
+            }
+            None => {
+                warn!(
+                    "Dropping job {}, task {} no longer exists",
+                    id, job.task_name
+                );
+                None
+            }
+        };
+
+        match next {
+            Some(time) => Some(Fire { time, ..fire }),
+            None => {
+                self.remove_job(id);
+                None
+            }
+        }
+    }
+
+    /// Notices the wall clock moving apart from steady time, which happens when it's changed or
+    /// the machine was asleep, and counts runs due before `now` as missed.
+    fn check_clock(&mut self, now: DateTime<Utc>) {
+        let steady = self.clock.steady();
+        let (last_now, last_steady) = self.last_check;
+        let elapsed = TimeDelta::from_std(steady.duration_since(last_steady)).unwrap_or_default();
+        let drift = (now - last_now - elapsed).abs();
+
+        if drift.to_std().is_ok_and(|drift| drift > CLOCK_JUMP) {
+            info!(
+                "Clock moved {}s apart from steady time, runs due before now were missed",
+                drift.num_seconds()
+            );
+            self.awake_since = now;
+        }
+        self.last_check = (now, steady);
+    }
+
+    /// Forgets finished runs and aborts any past their `max_runtime`. A run with an attempt in
+    /// progress is only flagged, and keeps its place and concurrency permit until Lua stops the
+    /// attempt. Lua can't interrupt a registered function, so an attempt blocked in one, such as
+    /// `http_get`, only stops once the function returns.
+    fn watch_runs(&mut self, now: DateTime<Utc>) {
+        let job_queue = &self.job_queue;
+        self.running.retain(|run| {
+            if run.handle.is_finished() {
+                return false;
+            }
+            if run.deadline.is_none_or(|deadline| now < deadline) {
+                return true;
+            }
+            if !run.abort.swap(true, Ordering::Relaxed) {
+                error!("Task {} exceeded its max runtime, aborting", run.task_name);
+            }
+            // Holding the attempt keeps the run from starting another while it's aborted.
+            match run.in_flight.try_lock() {
+                Ok(attempt) if attempt.is_none() => {
+                    run.handle.abort();
+                    job_queue.lock().unwrap().remove_run(&run.id);
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+}
+
+fn warn_if_late(fire: &Fire, now: DateTime<Utc>) {
+    if (now - fire.time)
+        .to_std()
+        .is_ok_and(|late| late > LATE_AFTER)
+    {
+        warn!(
+            "Task {} was held up by {}s",
+            fire.task_name,
+            (now - fire.time).num_seconds()
+        );
+    }
+}
+
+/// Adds catch-up runs of `task` to those due. Several are run one after another.
+fn push_catch_up(due: &mut Vec<(config::Task, usize)>, mut task: config::Task, runs: usize) {
+    if runs > 1 {
+        task.policy.concurrency = Concurrency::Queue;
+    }
+    if runs > 0 {
+        due.push((task, runs));
+    }
+}
+
+/// Works out how many runs of `task` to start for times it was due but didn't run, and records
+/// them as handled. A task that has never been seen is only recorded.
+fn catch_up(
+    last_runs: &mut LastRuns,
+    task: &config::Task,
+    schedule: &TaskSchedule,
+    now: DateTime<Utc>,
+) -> usize {
+    let Some(last_run) = last_runs.get(&task.name) else {
+        last_runs.set(&task.name, now);
+        return 0;
+    };
+
+    let runs = missed_runs(task, schedule, last_run, now);
+    last_runs.set(&task.name, now);
+    runs
+}
+
+/// Works out how many runs of `task` to start for times after `last_run` it was due but didn't
+/// run, following its catch-up policy.
+fn missed_runs(
+    task: &config::Task,
+    schedule: &TaskSchedule,
+    last_run: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> usize {
+    let missed = schedule
+        .upcoming(last_run)
+        .take_while(|time| *time <= now)
+        .take(MAX_CATCH_UP_RUNS + 1)
+        .count();
+    if missed == 0 {
+        return 0;
+    }
+
+    let count = if missed > MAX_CATCH_UP_RUNS {
+        format!("over {}", MAX_CATCH_UP_RUNS)
+    } else {
+        missed.to_string()
+    };
+    match task.policy.catch_up {
+        CatchUp::Skip => {
+            info!("Skipping {} missed run(s) of task {}", count, task.name);
+            0
+        }
+        CatchUp::RunOnce => {
+            info!(
+                "Catching up on {} missed run(s) of task {} with one run",
+                count, task.name
+            );
+            1
+        }
+        CatchUp::RunAll => {
+            if missed > MAX_CATCH_UP_RUNS {
+                warn!(
+                    "Task {} missed {} runs, only catching up on {}",
+                    task.name, count, MAX_CATCH_UP_RUNS
+                );
+            } else {
+                info!(
+                    "Catching up on {} missed run(s) of task {}",
+                    count, task.name
+                );
+            }
+            missed.min(MAX_CATCH_UP_RUNS)
+        }
+    }
+}
+
+/// A run's entry in the job queue, kept up to date so the run can be resumed after a restart.
+struct RunEntry {
+    id: String,
+    job_queue: Arc<StdMutex<JobQueue>>,
+    /// Attempts started before the run was resumed.
+    attempts: u32,
+}
+
+/// Runs `task`, trying again after failures with exponential backoff until it succeeds or is out
+/// of retries. Each attempt is kept in `in_flight` while it runs.
+async fn run_with_retries(
+    task: config::Task,
+    task_manager: Arc<Mutex<TaskManager>>,
+    abort: Arc<AtomicBool>,
+    entry: RunEntry,
+    in_flight: Attempt,
+) {
+    let policy = &task.policy;
+    let set_state = |state, attempts| {
+        entry
+            .job_queue
+            .lock()
+            .unwrap()
+            .set_run_state(&entry.id, state, attempts)
+    };
+
+    let gave_up = 'attempts: {
+        for attempt in entry.attempts..=policy.retries {
+            if attempt > 0 {
+                set_state(RunState::Waiting, attempt);
+                let delay = backoff(policy, attempt - 1);
+                info!(
+                    "Retrying task {} in {:?}, attempt {} of {}",
+                    task.name,
+                    delay,
+                    attempt + 1,
+                    policy.retries + 1
+                );
+                sleep(delay).await;
+            }
+
+            set_state(RunState::Running, attempt + 1);
+            // Held from before the attempt starts until it's finished with, so an attempt is never
+            // running without being in `in_flight`.
+            let mut current = in_flight.lock().await;
+            let scheduled = task_manager
+                .lock()
+                .await
+                .schedule(Task {
+                    task_name: task.name.clone(),
+                    params: JsonValue::Object(task.params.clone()),
+                    timeout: policy.timeout.map(Duration::from_secs),
+                    abort: abort.clone(),
+                })
+                .await
+                .map_err(|e| e.to_string());
+            // A hot reload can remove the task while this run waited for its turn.
+            let run = match scheduled {
+                Ok(run) => run,
+                Err(e) => {
+                    error!("Dropping run of task {}: {}", task.name, e);
+                    break 'attempts false;
+                }
+            };
+
+            let finished = current.insert(run).await;
+            *current = None;
+            drop(current);
+
+            match finished {
+                Ok(run) => {
+                    log_run(&run);
+                    if matches!(run.outcome, TaskOutcome::Success(_)) {
+                        break 'attempts false;
+                    }
+                }
+                Err(e) => error!("Task {} panicked: {}", task.name, e),
+            }
+
+            if abort.load(Ordering::Relaxed) {
+                break 'attempts false;
+            }
+        }
+        true
+    };
+
+    if gave_up && policy.retries > 0 {
+        error!(
+            "Task {} failed {} times, giving up until it's next due",
+            task.name,
+            policy.retries + 1
+        );
+    }
+    entry.job_queue.lock().unwrap().remove_run(&entry.id);
+}
+
+/// How long to wait before retry number `retry`, counting from zero. The delay doubles with each
+/// retry and a random part of up to half of it is taken off so retries of tasks that failed
+/// together spread out.
+fn backoff(policy: &RunPolicy, retry: u32) -> Duration {
+    let delay = Duration::from_secs(policy.retry_delay)
+        .saturating_mul(2u32.saturating_pow(retry))
+        .min(MAX_RETRY_DELAY);
+    delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..0.5))
+}
+
+pub fn log_run(run: &TaskRun) {
+    match &run.outcome {
+        TaskOutcome::Success(result) => {
+            info!("Task {} finished in {:?}", run.task_name, run.duration);
+            debug!("Task {} returned {}", run.task_name, result);
+        }
+        TaskOutcome::Failed(e) => {
+            error!(
+                "Task {} failed after {:?}: {}",
+                run.task_name, run.duration, e
+            )
+        }
+        TaskOutcome::TimedOut => warn!("Task {} timed out after {:?}", run.task_name, run.duration),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use {
+        serde_json::json,
+        tokio::{sync::watch, time::timeout},
+    };
+
+    async fn run_task(
+        task_manager: &mut TaskManager,
+        task_name: &str,
+        params: JsonValue,
+    ) -> TaskRun {
+        task_manager
+            .schedule(Task {
+                task_name: task_name.to_string(),
+                params,
+                timeout: Some(Duration::from_millis(100)),
+                abort: Default::default(),
+            })
+            .await
+            .unwrap()
+            .await
+            .unwrap()
+    }
+
+    fn script(tasks: &[(&str, &str)]) -> Script {
+        Script {
+            path: PathBuf::from("./scripts/test.lua"),
+            secrets: vec![],
+            tasks: tasks
+                .iter()
+                .map(|(name, handler)| config::Task {
+                    name: name.to_string(),
+                    handler: Some(handler.to_string()),
+                    when: When::Cron(String::from("0 * * * * * *")),
+                    timezone: None,
+                    params: Default::default(),
+                    policy: Default::default(),
+                    unknown: Default::default(),
+                })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_params() {
+        let script = script(&[("EchoTask", "Echo")]);
+        let mut task_manager = TaskManager::new().await.unwrap();
+        task_manager
+            .register_script(
+                r#"
+Echo = {}
+function Echo.setup() end
+function Echo.execute(params)
+    if params.fail then
+        error("asked to fail")
+    end
+    return { location = params.location, days = params.days + 1 }
+end
+"#,
+                &script,
+            )
+            .await
+            .unwrap();
+
+        // Params are data, so quotes and Lua syntax pass through untouched.
+        let location = "Ruston\"}) os.exit() --";
+        let run = run_task(
+            &mut task_manager,
+            "EchoTask",
+            json!({ "location": location, "days": 3 }),
+        )
+        .await;
+        assert_eq!(run.task_name, "EchoTask");
+        assert_eq!(
+            run.outcome,
+            TaskOutcome::Success(json!({ "location": location, "days": 4 }))
+        );
+
+        let run = run_task(&mut task_manager, "EchoTask", json!({ "fail": true })).await;
+        assert!(matches!(run.outcome, TaskOutcome::Failed(e) if e.contains("asked to fail")));
+    }
+
+    #[tokio::test]
+    async fn test_task_hooks() {
+        let contents = r#"
+Events = {}
+
+Hooked = {}
+function Hooked.setup() table.insert(Events, "setup") end
+function Hooked.teardown() table.insert(Events, "teardown") end
+function Hooked.execute(params)
+    if params.mode == "fail" then
+        error("failed")
+    elseif params.mode == "hang" then
+        while true do end
+    end
+    return params.mode
+end
+function Hooked.on_success(result) table.insert(Events, "success " .. result) end
+function Hooked.on_error(err) table.insert(Events, "error") end
+function Hooked.on_timeout() table.insert(Events, "timeout") end
+"#;
+        let script = script(&[("First", "Hooked"), ("Second", "Hooked")]);
+        let mut task_manager = TaskManager::new().await.unwrap();
+        task_manager
+            .register_script(contents, &script)
+            .await
+            .unwrap();
+
+        for mode in ["ok", "fail", "hang"] {
+            run_task(&mut task_manager, "First", json!({ "mode": mode })).await;
+        }
+        assert_eq!(
+            run_task(&mut task_manager, "First", json!({ "mode": "hang" }))
+                .await
+                .outcome,
+            TaskOutcome::TimedOut
+        );
+
+        // The handler is still used by Second, so it isn't torn down yet.
+        task_manager.unregister_task("First").await;
+        let unregistered = task_manager
+            .schedule(Task {
+                task_name: String::from("First"),
+                params: json!({}),
+                timeout: None,
+                abort: Default::default(),
+            })
+            .await;
+        assert_eq!(
+            unregistered.err().map(|e| e.to_string()),
+            Some(String::from("task First is no longer registered"))
+        );
+        task_manager
+            .register_script(&contents.replace("Events = {}", ""), &script)
+            .await
+            .unwrap();
+        task_manager.shutdown().await;
+
+        let lua = task_manager.lua.lock().await;
+        let events: Vec<String> = lua
+            .from_value(lua.globals().get("Events").unwrap())
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                "setup",
+                "success ok",
+                "error",
+                "timeout",
+                "timeout",
+                "teardown",
+                "setup",
+                "teardown"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cassette() {
+        let path = std::env::temp_dir().join(format!("salient-runs-{}", std::process::id()));
+        let script = script(&[("LookupTask", "Lookup")]);
+        let contents = r#"
+Lookup = {}
+function Lookup.setup() end
+function Lookup.execute(params)
+    return { found = lookup(params.key), secret = secret("TOKEN") }
+end
+"#;
+
+        let mut task_manager = TaskManager::new().await.unwrap();
+        task_manager.set_cassette(Cassette::record(&path).unwrap());
+        task_manager
+            .register_function("lookup", |_, key| json!(format!("recorded {}", key)))
+            .await
+            .unwrap();
+        task_manager
+            .register_live_function("secret", |_, _| json!("first"))
+            .await
+            .unwrap();
+        task_manager
+            .register_script(contents, &script)
+            .await
+            .unwrap();
+        run_task(&mut task_manager, "LookupTask", json!({ "key": "a" })).await;
+
+        let mut cassette = Cassette::replay(&path).unwrap();
+        let runs = cassette.take_runs().unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].task, "LookupTask");
+        assert_eq!(runs[0].handler, "Lookup");
+
+        let mut task_manager = TaskManager::new().await.unwrap();
+        task_manager.set_cassette(cassette);
+        task_manager
+            .register_function("lookup", |_, _| panic!("replay ran a live call"))
+            .await
+            .unwrap();
+        task_manager
+            .register_live_function("secret", |_, _| json!("second"))
+            .await
+            .unwrap();
+        task_manager
+            .register_script(contents, &script)
+            .await
+            .unwrap();
+        let run = run_task(&mut task_manager, "LookupTask", runs[0].params.clone()).await;
+        assert_eq!(
+            run.outcome,
+            TaskOutcome::Success(json!({ "found": "recorded \"a\"", "secret": "second" }))
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_print() {
+        let task_manager = TaskManager::new().await.unwrap();
+        let lua = task_manager.lua.lock().await;
+        let args = lua.load("return 1, 'a', nil, true").eval().unwrap();
+        assert_eq!(print_line(&lua, args).unwrap(), "1\ta\tnil\ttrue");
+        lua.load("print('printed', {})").exec().unwrap();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RunPolicy {
+            retry_delay: 10,
+            ..Default::default()
+        };
+
+        for (retry, full) in [(0, 10), (1, 20), (2, 40), (20, 60 * 60)] {
+            let delay = backoff(&policy, retry);
+            assert!(delay <= Duration::from_secs(full));
+            assert!(delay >= Duration::from_secs(full) / 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let mut script = script(&[("Flaky", "Flaky")]);
+        script.tasks[0].policy = RunPolicy {
+            retries: 3,
+            retry_delay: 0,
+            ..Default::default()
+        };
+        let mut task_manager = TaskManager::new().await.unwrap();
+        task_manager
+            .register_script(
+                r#"
+Flaky = { attempts = 0 }
+function Flaky.setup() end
+function Flaky.execute()
+    Flaky.attempts = Flaky.attempts + 1
+    if Flaky.attempts < 3 then
+        error("not yet")
+    end
+end
+"#,
+                &script,
+            )
+            .await
+            .unwrap();
+        let task_manager = Arc::new(Mutex::new(task_manager));
+
+        let job_queue = Arc::new(StdMutex::new(
+            JobQueue::load(&jobs_path("retries")).unwrap(),
+        ));
+        job_queue.lock().unwrap().add_run(
+            "Flaky-1",
+            QueuedRun {
+                task_name: String::from("Flaky"),
+                params: Map::new(),
+                state: RunState::Waiting,
+                attempts: 0,
+                started: Utc::now(),
+            },
+        );
+        let entry = RunEntry {
+            id: String::from("Flaky-1"),
+            job_queue: job_queue.clone(),
+            attempts: 0,
+        };
+        run_with_retries(
+            script.tasks[0].clone(),
+            task_manager.clone(),
+            Default::default(),
+            entry,
+            Default::default(),
+        )
+        .await;
+
+        let task_manager = task_manager.lock().await;
+        let lua = task_manager.lua.lock().await;
+        let attempts: i64 = lua.load("return Flaky.attempts").eval().unwrap();
+        assert_eq!(attempts, 3);
+        // The run is done with once it succeeds, so it isn't resumed after a restart.
+        assert_eq!(job_queue.lock().unwrap().runs().count(), 0);
+
+        fs::remove_file(jobs_path("retries")).unwrap();
+    }
+
+    #[test]
+    fn test_task_schedule() {
+        let from = "2026-11-05T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let upcoming = |when: When, timezone: Tz| {
+            TaskSchedule::new(&when, timezone)
+                .unwrap()
+                .upcoming(from)
+                .take(3)
+                .map(|time| time.to_rfc3339())
+                .collect::<Vec<_>>()
+        };
+        let every = |every: &str| When::Every(every.to_string());
+        let at = |at: &str| When::At(at.to_string());
+
+        assert_eq!(
+            upcoming(every("15m"), Tz::UTC),
+            [
+                "2026-11-05T12:15:00+00:00",
+                "2026-11-05T12:30:00+00:00",
+                "2026-11-05T12:45:00+00:00"
+            ]
+        );
+        assert_eq!(
+            upcoming(every("1h 30m"), Tz::UTC),
+            [
+                "2026-11-05T13:30:00+00:00",
+                "2026-11-05T15:00:00+00:00",
+                "2026-11-05T16:30:00+00:00"
+            ]
+        );
+        // The 5th is a Thursday and Chicago is six hours behind UTC in November.
+        assert_eq!(
+            upcoming(every("weekdays at 08:30"), Tz::America__Chicago),
+            [
+                "2026-11-05T14:30:00+00:00",
+                "2026-11-06T14:30:00+00:00",
+                "2026-11-09T14:30:00+00:00"
+            ]
+        );
+        assert_eq!(
+            upcoming(every("Saturday and sunday at 9:00"), Tz::UTC),
+            [
+                "2026-11-07T09:00:00+00:00",
+                "2026-11-08T09:00:00+00:00",
+                "2026-11-14T09:00:00+00:00"
+            ]
+        );
+        for phrase in ["daily at 9am", "every day at 09:00", "Every day at 9:00 AM"] {
+            assert_eq!(
+                upcoming(every(phrase), Tz::UTC),
+                [
+                    "2026-11-06T09:00:00+00:00",
+                    "2026-11-07T09:00:00+00:00",
+                    "2026-11-08T09:00:00+00:00"
+                ],
+                "{}",
+                phrase
+            );
+        }
+        assert_eq!(
+            upcoming(every("weekdays at 12:30pm"), Tz::UTC),
+            [
+                "2026-11-05T12:30:00+00:00",
+                "2026-11-06T12:30:00+00:00",
+                "2026-11-09T12:30:00+00:00"
+            ]
+        );
+        assert_eq!(
+            upcoming(every("friday at 12am"), Tz::UTC),
+            [
+                "2026-11-06T00:00:00+00:00",
+                "2026-11-13T00:00:00+00:00",
+                "2026-11-20T00:00:00+00:00"
+            ]
+        );
+        assert_eq!(
+            upcoming(at("2026-12-01T09:00"), Tz::America__Chicago),
+            ["2026-12-01T15:00:00+00:00"]
+        );
+        assert_eq!(
+            upcoming(at("2026-12-01T09:00:00+01:00"), Tz::America__Chicago),
+            ["2026-12-01T08:00:00+00:00"]
+        );
+        assert!(upcoming(at("2026-11-01T09:00"), Tz::UTC).is_empty());
+
+        for invalid in [
+            every("0m"),
+            every("weekdays"),
+            every("funday at 08:30"),
+            every("weekdays at 25:00"),
+            every("daily at 13pm"),
+            every("daily at 9"),
+            at("tomorrow"),
+            // Clocks skip from 2am to 3am that night.
+            at("2026-03-08T02:30"),
+            When::Cron(String::from("0 0 0")),
+        ] {
+            assert!(TaskSchedule::new(&invalid, Tz::America__Chicago).is_err());
+        }
+    }
+
+    #[test]
+    fn test_catch_up() {
+        let path = std::env::temp_dir().join(format!("salient-schedule-{}", std::process::id()));
+        let mut last_runs = LastRuns::load(&path).unwrap();
+        let hourly =
+            TaskSchedule::new(&When::Cron(String::from("0 0 * * * * *")), Tz::UTC).unwrap();
+        let mut task = script(&[("Hourly", "Hourly")]).tasks.remove(0);
+        let time = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+
+        // A task that has never run only starts being tracked.
+        let now = time("2026-03-09T12:30:00Z");
+        assert_eq!(catch_up(&mut last_runs, &task, &hourly, now), 0);
+        assert_eq!(last_runs.get("Hourly"), Some(now));
+
+        let now = time("2026-03-09T15:30:00Z");
+        for (policy, expected) in [
+            (CatchUp::RunAll, 3),
+            (CatchUp::RunOnce, 1),
+            (CatchUp::Skip, 0),
+        ] {
+            last_runs.set("Hourly", time("2026-03-09T12:30:00Z"));
+            task.policy.catch_up = policy;
+            assert_eq!(catch_up(&mut last_runs, &task, &hourly, now), expected);
+            assert_eq!(catch_up(&mut last_runs, &task, &hourly, now), 0);
+        }
+
+        // 9am in Chicago is 14:00 UTC once daylight saving time has started.
+        task.policy.catch_up = CatchUp::RunOnce;
+        for (timezone, expected) in [(Tz::UTC, 0), (Tz::America__Chicago, 1)] {
+            last_runs.set("Hourly", time("2026-03-09T13:30:00Z"));
+            let now = time("2026-03-09T14:30:00Z");
+            let daily = TaskSchedule::new(&When::Cron(String::from("0 0 9 * * * *")), timezone);
+            assert_eq!(
+                catch_up(&mut last_runs, &task, &daily.unwrap(), now),
+                expected
+            );
+        }
+
+        // State survives a restart.
+        assert_eq!(
+            LastRuns::load(&path).unwrap().get("Hourly"),
+            Some(time("2026-03-09T14:30:00Z"))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// A clock that only moves when told to.
+    #[derive(Clone)]
+    struct ManualClock {
+        now: Arc<watch::Sender<DateTime<Utc>>>,
+        steady: Arc<StdMutex<Instant>>,
+    }
+
+    impl ManualClock {
+        fn new(now: DateTime<Utc>) -> Self {
+            Self {
+                now: Arc::new(watch::Sender::new(now)),
+                steady: Arc::new(StdMutex::new(Instant::now())),
+            }
+        }
+
+        /// Moves the clock forward as if the time had passed.
+        fn set(&self, now: DateTime<Utc>) {
+            let elapsed = (now - self.now()).to_std().unwrap_or_default();
+            *self.steady.lock().unwrap() += elapsed;
+            self.now.send_replace(now);
+        }
+
+        /// Moves the clock without time passing, like it being changed or the machine sleeping.
+        fn jump(&self, now: DateTime<Utc>) {
+            self.now.send_replace(now);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.now.borrow()
+        }
+
+        fn steady(&self) -> Instant {
+            *self.steady.lock().unwrap()
+        }
+
+        fn sleep_until(&self, time: DateTime<Utc>) -> impl Future<Output = ()> + Send {
+            let mut now = self.now.subscribe();
+            async move {
+                let _ = now.wait_for(|now| *now >= time).await;
+            }
+        }
+    }
+
+    fn scheduler(
+        name: &str,
+        now: &str,
+        crons: &[(&str, &str)],
+    ) -> (Scheduler<ManualClock>, ManualClock) {
+        let scheduling = Scheduling {
+            state: std::env::temp_dir().join(format!("salient-{}-{}", name, std::process::id())),
+            jobs: jobs_path(name),
+            ..Default::default()
+        };
+        let clock = ManualClock::new(now.parse().unwrap());
+        let mut scheduler = Scheduler::with_clock(&scheduling, clock.clone()).unwrap();
+        let mut script = script(
+            &crons
+                .iter()
+                .map(|(name, _)| (*name, *name))
+                .collect::<Vec<_>>(),
+        );
+        for (task, (_, cron)) in script.tasks.iter_mut().zip(crons) {
+            task.when = When::Cron(cron.to_string());
+            scheduler.register_task(task).unwrap();
+        }
+        (scheduler, clock)
+    }
+
+    fn jobs_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("salient-{}-jobs-{}", name, std::process::id()))
+    }
+
+    fn due_names(scheduler: &mut Scheduler<ManualClock>, now: DateTime<Utc>) -> Vec<String> {
+        scheduler
+            .take_due(now)
+            .into_iter()
+            .map(|(task, runs)| format!("{} x{}", task.name, runs))
+            .collect()
+    }
+
+    #[test]
+    fn test_fire_order() {
+        let (mut scheduler, clock) = scheduler(
+            "order",
+            "2026-03-09T12:00:00Z",
+            &[
+                ("Quarter", "*/15 * * * * * *"),
+                ("Third", "*/20 * * * * * *"),
+            ],
+        );
+
+        let mut fired = vec![];
+        for _ in 0..6 {
+            let wake = scheduler.next_wake().unwrap();
+            clock.set(wake);
+            fired.push((
+                wake.format("%M:%S").to_string(),
+                due_names(&mut scheduler, wake),
+            ));
+        }
+        assert_eq!(
+            fired,
+            [
+                ("00:15".to_string(), vec!["Quarter x1".to_string()]),
+                ("00:20".to_string(), vec!["Third x1".to_string()]),
+                ("00:30".to_string(), vec!["Quarter x1".to_string()]),
+                ("00:40".to_string(), vec!["Third x1".to_string()]),
+                ("00:45".to_string(), vec!["Quarter x1".to_string()]),
+                (
+                    "01:00".to_string(),
+                    vec!["Quarter x1".to_string(), "Third x1".to_string()]
+                ),
+            ]
+        );
+
+        // Removed tasks don't fire again, even though they're still in the queue.
+        let tasks = [scheduler.tasks["Quarter"].task.clone()];
+        scheduler.sync_tasks(&tasks, &Default::default()).unwrap();
+        let wake = "2026-03-09T12:01:20Z".parse().unwrap();
+        assert_eq!(due_names(&mut scheduler, wake), ["Quarter x1"]);
+        assert_eq!(
+            scheduler.next_wake(),
+            Some("2026-03-09T12:01:30Z".parse().unwrap())
+        );
+
+        fs::remove_file(&scheduler.last_runs.path).unwrap();
+    }
+
+    #[test]
+    fn test_clock_jump() {
+        let (mut scheduler, clock) = scheduler(
+            "jump",
+            "2026-03-09T12:00:00Z",
+            &[("Skipped", "0 0 * * * * *"), ("Caught", "0 0 * * * * *")],
+        );
+        let mut caught = scheduler.tasks["Caught"].task.clone();
+        caught.policy.catch_up = CatchUp::RunAll;
+        scheduler
+            .sync_tasks(
+                &[scheduler.tasks["Skipped"].task.clone(), caught],
+                &Default::default(),
+            )
+            .unwrap();
+
+        // Three hours pass without the timer firing, like when the machine is asleep.
+        let now = "2026-03-09T15:30:00Z".parse().unwrap();
+        clock.jump(now);
+        assert_eq!(due_names(&mut scheduler, now), ["Caught x3"]);
+        assert_eq!(
+            scheduler.next_wake(),
+            Some("2026-03-09T16:00:00Z".parse().unwrap())
+        );
+
+        // Held up for two hours while awake, such as by a long reload, every task runs once.
+        let now = "2026-03-09T18:05:00Z".parse().unwrap();
+        clock.set(now);
+        assert_eq!(due_names(&mut scheduler, now), ["Caught x1", "Skipped x1"]);
+        assert_eq!(
+            scheduler.next_wake(),
+            Some("2026-03-09T19:00:00Z".parse().unwrap())
+        );
+
+        fs::remove_file(&scheduler.last_runs.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let (mut scheduler, clock) = scheduler(
+            "wait",
+            "2026-03-09T12:00:00Z",
+            &[("Minutely", "0 * * * * * *")],
+        );
+        let path = scheduler.last_runs.path.clone();
+
+        let wait = scheduler.wait();
+        tokio::pin!(wait);
+        assert!(timeout(Duration::from_millis(10), &mut wait).await.is_err());
+        clock.set("2026-03-09T12:01:00Z".parse().unwrap());
+        assert!(timeout(Duration::from_secs(1), &mut wait).await.is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let (mut scheduler, clock) = scheduler(
+            "jobs",
+            "2026-03-09T12:00:00Z",
+            &[("Hourly", "0 0 * * * * *")],
+        );
+        let handle = scheduler.handle();
+        let schedule = |spec: JsonValue| handle.schedule(serde_json::from_value(spec).unwrap());
+
+        let once = schedule(json!({
+            "task": "Hourly",
+            "at": "2026-03-09T12:30",
+            "params": { "note": "once" }
+        }))
+        .unwrap();
+        schedule(json!({ "task": "Hourly", "after": "50m", "params": { "note": "after" } }))
+            .unwrap();
+        let repeat = schedule(json!({ "task": "Hourly", "cron": "0 15 * * * * *" })).unwrap();
+        let cancelled = schedule(json!({ "task": "Hourly", "at": "2026-03-09T12:45" })).unwrap();
+        assert!(handle.cancel(&cancelled));
+        assert!(!handle.cancel(&cancelled));
+
+        assert!(schedule(json!({ "task": "Missing", "after": "2h" })).is_err());
+        assert!(schedule(json!({ "task": "Hourly", "at": "2000-01-01T00:00" })).is_err());
+        assert!(schedule(json!({ "task": "Hourly", "after": "soon" })).is_err());
+
+        for _ in 0..5 {
+            scheduler.wait().await;
+        }
+
+        let mut fired = vec![];
+        for _ in 0..6 {
+            let wake = scheduler.next_wake().unwrap();
+            clock.set(wake);
+            let notes = scheduler
+                .take_due(wake)
+                .into_iter()
+                .map(|(task, _)| task.params.get("note").cloned())
+                .collect::<Vec<_>>();
+            fired.push((wake.format("%H:%M").to_string(), notes));
+        }
+        assert_eq!(
+            fired,
+            [
+                ("12:15".to_string(), vec![None]),
+                ("12:30".to_string(), vec![Some(json!("once"))]),
+                ("12:45".to_string(), vec![]),
+                ("12:50".to_string(), vec![Some(json!("after"))]),
+                ("13:00".to_string(), vec![None]),
+                ("13:15".to_string(), vec![None]),
+            ]
+        );
+        assert!(!handle.cancel(&once));
+        assert!(handle.cancel(&repeat));
+
+        fs::remove_file(&scheduler.last_runs.path).unwrap();
+        fs::remove_file(&scheduler.scheduling.jobs).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let mut job_queue = JobQueue::load(&jobs_path("resume")).unwrap();
+        let run = |task_name: &str| QueuedRun {
+            task_name: task_name.to_string(),
+            params: json!({ "step": 2 }).as_object().unwrap().clone(),
+            state: RunState::Running,
+            attempts: 1,
+            started: "2026-03-09T11:59:00Z".parse().unwrap(),
+        };
+        job_queue.add_run("Flaky-1", run("Flaky"));
+        job_queue.add_run("Spent-1", run("Spent"));
+        job_queue.add_job(
+            "Flaky-2",
+            QueuedJob {
+                task_name: String::from("Flaky"),
+                when: When::At(String::from("2026-03-09T12:30")),
+                params: Map::new(),
+                last_run: "2026-03-09T11:00:00Z".parse().unwrap(),
+            },
+        );
+        drop(job_queue);
+
+        let (mut scheduler, _) = scheduler(
+            "resume",
+            "2026-03-09T12:00:00Z",
+            &[("Flaky", "0 0 * * * * *"), ("Spent", "0 0 * * * * *")],
+        );
+        // Flaky has a retry left after the interrupted attempt and Spent doesn't.
+        let mut tasks = vec![
+            scheduler.tasks["Flaky"].task.clone(),
+            scheduler.tasks["Spent"].task.clone(),
+        ];
+        tasks[0].policy.retries = 1;
+        tasks[0].policy.retry_delay = 0;
+        let scheduling = scheduler.scheduling.clone();
+        scheduler.sync_tasks(&tasks, &scheduling).unwrap();
+
+        let mut task_manager = TaskManager::new().await.unwrap();
+        task_manager
+            .register_script(
+                r#"
+Flaky = { steps = {} }
+function Flaky.setup() end
+function Flaky.execute(params)
+    table.insert(Flaky.steps, params.step)
+end
+
+Spent = {}
+function Spent.setup() end
+function Spent.execute()
+    error("out of retries")
+end
+"#,
+                &script(&[("Flaky", "Flaky"), ("Spent", "Spent")]),
+            )
+            .await
+            .unwrap();
+        let task_manager = Arc::new(Mutex::new(task_manager));
+
+        scheduler.resume(task_manager.clone());
+        for run in scheduler.running.drain(..) {
+            run.handle.await.unwrap();
+        }
+
+        {
+            let task_manager = task_manager.lock().await;
+            let lua = task_manager.lua.lock().await;
+            let steps: Vec<i64> = lua
+                .from_value(lua.load("return Flaky.steps").eval().unwrap())
+                .unwrap();
+            assert_eq!(steps, [2]);
+        }
+
+        let job_queue = JobQueue::load(&scheduling.jobs).unwrap();
+        assert_eq!(job_queue.runs().count(), 0);
+        assert_eq!(job_queue.jobs().count(), 1);
+        assert_eq!(
+            scheduler.next_wake(),
+            Some("2026-03-09T12:30:00Z".parse().unwrap())
+        );
+        assert!(scheduler.handle().cancel("Flaky-2"));
+
+        fs::remove_file(&scheduler.last_runs.path).unwrap();
+        fs::remove_file(&scheduling.jobs).unwrap();
+    }
+
+    // Lua holds a worker thread until it's interrupted, so another is needed to keep time and
+    // stop it.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_stop() {
+        let (mut scheduler, clock) = scheduler(
+            "stop",
+            "2026-03-09T12:00:00Z",
+            &[("Blocked", "0 0 * * * * *"), ("Spinning", "0 0 * * * * *")],
+        );
+
+        let mut task_manager = TaskManager::new().await.unwrap();
+        // Stands in for a registered function such as `http_get`, which Lua can't interrupt.
+        task_manager
+            .register_function("block", |_, _| {
+                std::thread::sleep(Duration::from_millis(300));
+                JsonValue::Null
+            })
+            .await
+            .unwrap();
+        task_manager
+            .register_script(
+                r#"
+Runs = { Blocked = 0, Spinning = 0 }
+
+Blocked = {}
+function Blocked.setup() end
+function Blocked.execute()
+    Runs.Blocked = Runs.Blocked + 1
+    block()
+end
+
+Spinning = {}
+function Spinning.setup() end
+function Spinning.execute()
+    Runs.Spinning = Runs.Spinning + 1
+    while true do end
+end
+"#,
+                &script(&[("Blocked", "Blocked"), ("Spinning", "Spinning")]),
+            )
+            .await
+            .unwrap();
+        let task_manager = Arc::new(Mutex::new(task_manager));
+
+        for name in ["Blocked", "Spinning"] {
+            let task = scheduler.tasks[name].task.clone();
+            scheduler.start_run(task, task_manager.clone(), clock.now(), None);
+            sleep(Duration::from_millis(50)).await;
+        }
+        scheduler.stop().await;
+
+        // Blocked still finished once its function returned, so only Spinning is resumed.
+        let job_queue = JobQueue::load(&scheduler.scheduling.jobs).unwrap();
+        let queued = job_queue
+            .runs()
+            .map(|(_, run)| run.task_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(queued, ["Spinning"]);
+
+        {
+            let task_manager = task_manager.lock().await;
+            let lua = task_manager.lua.lock().await;
+            let runs: HashMap<String, i64> = lua
+                .from_value(lua.load("return Runs").eval().unwrap())
+                .unwrap();
+            assert_eq!(
+                runs,
+                HashMap::from([(String::from("Blocked"), 1), (String::from("Spinning"), 1)])
+            );
+        }
+
+        fs::remove_file(&scheduler.last_runs.path).unwrap();
+        fs::remove_file(&scheduler.scheduling.jobs).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_max_runtime() {
+        let (mut scheduler, clock) = scheduler(
+            "runtime",
+            "2026-03-09T12:00:00Z",
+            &[("Blocked", "0 0 * * * * *")],
+        );
+        let mut task = scheduler.tasks["Blocked"].task.clone();
+        task.policy.max_runtime = Some(60);
+
+        let mut task_manager = TaskManager::new().await.unwrap();
+        task_manager
+            .register_function("block", |_, _| {
+                std::thread::sleep(Duration::from_millis(300));
+                JsonValue::Null
+            })
+            .await
+            .unwrap();
+        task_manager
+            .register_script(
+                r#"
+Blocked = { runs = 0 }
+function Blocked.setup() end
+function Blocked.execute()
+    Blocked.runs = Blocked.runs + 1
+    block()
+end
+"#,
+                &script(&[("Blocked", "Blocked")]),
+            )
+            .await
+            .unwrap();
+        let task_manager = Arc::new(Mutex::new(task_manager));
+
+        scheduler.start_run(task.clone(), task_manager.clone(), clock.now(), None);
+        sleep(Duration::from_millis(50)).await;
+
+        // Past its max runtime but stuck in `block`, the run keeps its place, so the next is
+        // skipped rather than queued behind it.
+        clock.set("2026-03-09T12:02:00Z".parse().unwrap());
+        scheduler.watch_runs(clock.now());
+        assert_eq!(scheduler.running.len(), 1);
+        assert!(scheduler.running[0].abort.load(Ordering::Relaxed));
+        assert_eq!(
+            scheduler.next_wake(),
+            Some("2026-03-09T13:00:00Z".parse().unwrap())
+        );
+        scheduler.start_run(task, task_manager.clone(), clock.now(), None);
+        assert_eq!(scheduler.running.len(), 1);
+
+        for run in scheduler.running.drain(..) {
+            run.handle.await.unwrap();
+        }
+        {
+            let task_manager = task_manager.lock().await;
+            let lua = task_manager.lua.lock().await;
+            let runs: i64 = lua.load("return Blocked.runs").eval().unwrap();
+            assert_eq!(runs, 1);
+        }
+        let job_queue = JobQueue::load(&scheduler.scheduling.jobs).unwrap();
+        assert_eq!(job_queue.runs().count(), 0);
+
+        fs::remove_file(&scheduler.last_runs.path).unwrap();
+        fs::remove_file(&scheduler.scheduling.jobs).unwrap();
     }
 }
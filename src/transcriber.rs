@@ -0,0 +1,196 @@
+use std::path::Path;
+
+use {
+    anyhow::{bail, Context, Result},
+    hound::{SampleFormat, WavReader},
+    log::{debug, info},
+    serde::{Deserialize, Serialize},
+    whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters},
+};
+
+use crate::config::{Hub, Transcription};
+
+/// Whisper models expect 16kHz mono audio.
+const SAMPLE_RATE: u32 = 16_000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Segment {
+    /// Offset from the start of the audio in seconds.
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transcript {
+    pub text: String,
+    pub segments: Vec<Segment>,
+}
+
+pub struct Transcriber {
+    ctx: WhisperContext,
+    config: Transcription,
+}
+
+impl Transcriber {
+    pub fn new(config: &Transcription, hub: &Hub) -> Result<Self> {
+        let model_path = config
+            .source
+            .get_or_load(hub)
+            .with_context(|| "failed to get whisper model")?;
+
+        let params = WhisperContextParameters {
+            use_gpu: false,
+            ..Default::default()
+        };
+
+        let ctx = WhisperContext::new_with_params(
+            model_path
+                .to_str()
+                .context("whisper model path isn't valid UTF-8")?,
+            params,
+        )
+        .with_context(|| "unable to load whisper model")?;
+
+        info!("Loaded whisper model {:?}", config.source);
+
+        Ok(Self {
+            ctx,
+            config: config.clone(),
+        })
+    }
+
+    /// Transcribes a WAV file. `language` overrides the configured one and may be `auto`.
+    pub fn transcribe(&self, path: &Path, language: Option<&str>) -> Result<Transcript> {
+        let audio = read_wav(path)?;
+        debug!(
+            "Transcribing {} ({:.1}s)",
+            path.display(),
+            audio.len() as f64 / SAMPLE_RATE as f64
+        );
+
+        let mut state = self
+            .ctx
+            .create_state()
+            .with_context(|| "unable to create whisper state")?;
+
+        let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+        // Leaving the language unset keeps whisper's default of English.
+        if let Some(language) = language.or(self.config.language.as_deref()) {
+            params.set_language(Some(language));
+        }
+        params.set_print_progress(false);
+        params.set_print_realtime(false);
+        params.set_print_special(false);
+        params.set_print_timestamps(false);
+        if let Some(n_threads) = self.config.n_threads {
+            params.set_n_threads(n_threads as i32);
+        }
+
+        state
+            .full(params, &audio)
+            .with_context(|| "whisper failed to transcribe audio")?;
+
+        let mut segments = vec![];
+        for i in 0..state.full_n_segments()? {
+            // Whisper timestamps are in 10ms units.
+            segments.push(Segment {
+                start: state.full_get_segment_t0(i)? as f64 / 100.0,
+                end: state.full_get_segment_t1(i)? as f64 / 100.0,
+                text: state.full_get_segment_text_lossy(i)?.trim().to_string(),
+            });
+        }
+
+        let text = segments
+            .iter()
+            .map(|segment| segment.text.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        Ok(Transcript { text, segments })
+    }
+}
+
+/// Reads a WAV file as 16kHz mono samples in the range -1.0 to 1.0.
+fn read_wav(path: &Path) -> Result<Vec<f32>> {
+    let mut reader =
+        WavReader::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+    let spec = reader.spec();
+
+    let samples = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<f32>, _>>()?,
+        SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<Vec<f32>, _>>()?
+        }
+    };
+
+    if spec.channels == 0 {
+        bail!("{} has no audio channels", path.display());
+    }
+
+    Ok(resample(
+        &to_mono(&samples, spec.channels as usize),
+        spec.sample_rate,
+        SAMPLE_RATE,
+    ))
+}
+
+fn to_mono(samples: &[f32], channels: usize) -> Vec<f32> {
+    if channels == 1 {
+        return samples.to_vec();
+    }
+
+    samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+/// Linear resampling. Good enough for speech, which is all whisper needs.
+fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let ratio = from as f64 / to as f64;
+    let len = (samples.len() as f64 / ratio).floor() as usize;
+
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * ratio;
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let current = samples[index];
+            let next = samples.get(index + 1).copied().unwrap_or(current);
+            current + (next - current) * fraction
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_mono() {
+        assert_eq!(to_mono(&[0.5, -0.5, 1.0, 0.0], 2), vec![0.0, 0.5]);
+        assert_eq!(to_mono(&[0.25, 0.75], 1), vec![0.25, 0.75]);
+    }
+
+    #[test]
+    fn test_resample() {
+        assert_eq!(
+            resample(&[0.0, 1.0, 0.0, -1.0], 32_000, 16_000),
+            vec![0.0, 0.0]
+        );
+        assert_eq!(
+            resample(&[0.0, 1.0], 8_000, 16_000),
+            vec![0.0, 0.5, 1.0, 1.0]
+        );
+        assert_eq!(resample(&[0.1, 0.2], 16_000, 16_000), vec![0.1, 0.2]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use {
+    anyhow::{Context, Result},
+    log::{debug, warn},
+    notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _},
+    tokio::{
+        sync::mpsc::{unbounded_channel, UnboundedReceiver},
+        time::timeout,
+    },
+};
+
+/// How long to keep collecting changes after the first one. Editors often save a file in several
+/// steps and a reload should only happen once they're done.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Watches a set of files and directories for changes. Files are watched through their parent
+/// directory so they are still seen when an editor replaces them rather than writing in place.
+pub struct Watcher {
+    watcher: RecommendedWatcher,
+    events: UnboundedReceiver<PathBuf>,
+    /// Files that matter, by `normalize`d path.
+    files: HashSet<PathBuf>,
+    /// Directories where any file matters.
+    dirs: HashSet<PathBuf>,
+    /// Directories registered with the OS watcher.
+    watched: HashSet<PathBuf>,
+}
+
+impl Watcher {
+    pub fn new() -> Result<Self> {
+        let (sender, events) = unbounded_channel();
+
+        let watcher =
+            notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                Ok(event) if !event.kind.is_access() => {
+                    for path in event.paths {
+                        let _ = sender.send(path);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("File watch error: {}", e),
+            })
+            .with_context(|| "unable to create file watcher")?;
+
+        Ok(Self {
+            watcher,
+            events,
+            files: HashSet::new(),
+            dirs: HashSet::new(),
+            watched: HashSet::new(),
+        })
+    }
+
+    /// Replaces what is watched with `files` and every file in `dirs`.
+    pub fn watch(&mut self, files: Vec<PathBuf>, dirs: Vec<PathBuf>) -> Result<()> {
+        self.files = files.iter().map(|path| normalize(path)).collect();
+        self.dirs = dirs.iter().map(|path| normalize(path)).collect();
+
+        let mut wanted: HashSet<PathBuf> = self
+            .files
+            .iter()
+            .filter_map(|path| path.parent().map(Path::to_path_buf))
+            .collect();
+        wanted.extend(self.dirs.iter().cloned());
+        wanted.retain(|dir| dir.is_dir());
+
+        for dir in self.watched.difference(&wanted) {
+            let _ = self.watcher.unwatch(dir);
+        }
+        for dir in wanted.difference(&self.watched) {
+            self.watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("unable to watch {}", dir.display()))?;
+            debug!("Watching {}", dir.display());
+        }
+        self.watched = wanted;
+
+        Ok(())
+    }
+
+    /// Waits for watched files to change and returns their normalized paths.
+    pub async fn changed(&mut self) -> HashSet<PathBuf> {
+        let mut changed = HashSet::new();
+
+        while changed.is_empty() {
+            let Some(path) = self.events.recv().await else {
+                return std::future::pending().await;
+            };
+            self.collect(path, &mut changed);
+        }
+
+        while let Ok(Some(path)) = timeout(DEBOUNCE, self.events.recv()).await {
+            self.collect(path, &mut changed);
+        }
+
+        changed
+    }
+
+    fn collect(&self, path: PathBuf, changed: &mut HashSet<PathBuf>) {
+        let in_dir = path.parent().is_some_and(|dir| self.dirs.contains(dir));
+        if in_dir || self.files.contains(&path) {
+            changed.insert(path);
+        }
+    }
+}
+
+/// Resolves the directory part of `path` so it can be compared with paths reported by the OS.
+/// The file itself isn't resolved, which keeps symlinked files matching their link's events and
+/// lets paths to deleted files still be normalized.
+pub fn normalize(path: &Path) -> PathBuf {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+
+    fs::canonicalize(parent)
+        .map(|parent| parent.join(name))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch_changes() {
+        let dir = std::env::temp_dir().join(format!("salient-watch-{}", std::process::id()));
+        let fragments = dir.join("conf.d");
+        fs::create_dir_all(&fragments).unwrap();
+        let script = dir.join("test.lua");
+        fs::write(&script, "Test = {}").unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        watcher
+            .watch(vec![script.clone()], vec![fragments.clone()])
+            .unwrap();
+
+        fs::write(dir.join("unrelated.txt"), "").unwrap();
+        fs::write(&script, "Test = { changed = true }").unwrap();
+        fs::write(fragments.join("10-news.toml"), "").unwrap();
+
+        let changed = timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap();
+        assert!(changed.contains(&normalize(&script)));
+        assert!(changed.contains(&normalize(&fragments.join("10-news.toml"))));
+        assert!(!changed.contains(&normalize(&dir.join("unrelated.txt"))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}